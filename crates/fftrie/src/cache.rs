@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+// A fixed-budget cache keyed by offset, evicting with the CLOCK algorithm:
+// entries sit in a ring swept by a hand, each read sets the entry's reference
+// bit, and the hand evicts the first entry whose bit is already clear,
+// clearing bits as it passes. This approximates LRU without having to reorder
+// anything on a hit.
+pub(crate) struct ClockCache<V> {
+    budget: usize,
+    used: usize,
+    index: HashMap<u64, usize>,
+    slots: Vec<Option<Slot<V>>>,
+    free: Vec<usize>,
+    hand: usize,
+}
+
+struct Slot<V> {
+    key: u64,
+    value: V,
+    size: usize,
+    referenced: bool,
+}
+
+impl<V> ClockCache<V> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            index: HashMap::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            hand: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn get(&mut self, key: u64) -> Option<&V> {
+        let slot = self.slots[*self.index.get(&key)?].as_mut().unwrap();
+        slot.referenced = true;
+        Some(&slot.value)
+    }
+
+    /// Inserts `value`, charging `size` bytes against the budget, and returns
+    /// how many entries were evicted to make room. Values larger than the
+    /// whole budget are not cached.
+    pub fn insert(&mut self, key: u64, value: V, size: usize) -> usize {
+        self.remove(key);
+        if size > self.budget {
+            return 0;
+        }
+
+        let mut evicted = 0;
+        while self.used + size > self.budget {
+            self.evict_one();
+            evicted += 1;
+        }
+
+        let slot = Some(Slot { key, value, size, referenced: false });
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = slot;
+                idx
+            }
+            None => {
+                self.slots.push(slot);
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(key, idx);
+        self.used += size;
+        evicted
+    }
+
+    pub fn remove(&mut self, key: u64) -> Option<V> {
+        let idx = self.index.remove(&key)?;
+        let slot = self.slots[idx].take().unwrap();
+        self.used -= slot.size;
+        self.free.push(idx);
+        Some(slot.value)
+    }
+
+    fn evict_one(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+
+            let idx = self.hand;
+            self.hand += 1;
+            match &mut self.slots[idx] {
+                Some(slot) if slot.referenced => slot.referenced = false,
+                Some(slot) => {
+                    let key = slot.key;
+                    self.remove(key);
+                    return;
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let mut cache = ClockCache::new(100);
+        assert_eq!(cache.insert(1, "a", 40), 0);
+        assert_eq!(cache.insert(2, "b", 40), 0);
+        assert_eq!(cache.insert(3, "c", 40), 1);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.used(), 80);
+        assert_eq!(cache.get(1), None);
+
+        // Too big to ever fit.
+        assert_eq!(cache.insert(4, "d", 101), 0);
+        assert_eq!(cache.get(4), None);
+    }
+
+    #[test]
+    fn test_referenced_entries_get_a_second_chance() {
+        let mut cache = ClockCache::new(3);
+        cache.insert(1, "a", 1);
+        cache.insert(2, "b", 1);
+        cache.insert(3, "c", 1);
+        assert_eq!(cache.get(1), Some(&"a"));
+
+        cache.insert(4, "d", 1);
+        assert_eq!(cache.get(1), Some(&"a"));
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(3), Some(&"c"));
+        assert_eq!(cache.get(4), Some(&"d"));
+    }
+
+    #[test]
+    fn test_reinsert_replaces() {
+        let mut cache = ClockCache::new(10);
+        cache.insert(1, "a", 4);
+        cache.insert(1, "b", 6);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.used(), 6);
+        assert_eq!(cache.remove(1), Some("b"));
+        assert_eq!(cache.used(), 0);
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::compute_hash;
+use crate::nibbles::Nibbles;
+use crate::node::{Node, NodeHash, NodeRef};
+use crate::store::Store;
+
+/// Something wrong with a stored trie.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ProblemKind {
+    /// The node couldn't be read, e.g. because its offset is out of bounds.
+    Unreadable(String),
+    /// The node is one of its own ancestors.
+    Cycle,
+    MissingHash,
+    /// The stored hash doesn't match the one recomputed from the node.
+    HashMismatch { stored: Vec<u8>, computed: Vec<u8> },
+    EmptyExtension,
+    ExtensionWithoutChild,
+    /// An extension whose child is another extension, which should have been
+    /// merged into it.
+    ExtensionToExtension,
+    /// A branch with a single child and no value, which should have been an
+    /// extension or a leaf.
+    SingleChildBranch,
+    /// A branch without children, which should have been a leaf, or nothing
+    /// at all if it has no value either.
+    ChildlessBranch,
+}
+
+/// A problem found by `check`, with where it was found.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Problem {
+    /// The offset of the record holding the node. Inline nodes are reported
+    /// at the offset of their parent.
+    pub offset: u64,
+    /// The nibbles leading to the node from the root.
+    pub path: Vec<u8>,
+    pub kind: ProblemKind,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path: String = self.path.iter().map(|nibble| format!("{:x}", nibble)).collect();
+        write!(f, "offset {} path [{}]: ", self.offset, path)?;
+        match &self.kind {
+            ProblemKind::Unreadable(e) => write!(f, "unreadable node: {}", e),
+            ProblemKind::Cycle => write!(f, "node is its own ancestor"),
+            ProblemKind::MissingHash => write!(f, "node has no stored hash"),
+            ProblemKind::HashMismatch { stored, computed } => {
+                write!(f, "stored hash {} does not match computed {}", hex::encode(stored), hex::encode(computed))
+            }
+            ProblemKind::EmptyExtension => write!(f, "extension with an empty path"),
+            ProblemKind::ExtensionWithoutChild => write!(f, "extension without a child"),
+            ProblemKind::ExtensionToExtension => write!(f, "extension pointing at an extension"),
+            ProblemKind::SingleChildBranch => write!(f, "branch with a single child and no value"),
+            ProblemKind::ChildlessBranch => write!(f, "branch without children"),
+        }
+    }
+}
+
+/// What `check` found.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CheckReport {
+    /// Distinct nodes visited, inline ones included.
+    pub nodes: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Walks every node reachable from `root_offset` and reports every problem
+/// found: unreadable or cyclic offsets, hashes that don't match the nodes,
+/// and nodes that break the trie's structural invariants. Problems below a
+/// node don't stop the walk, but a node whose children couldn't all be
+/// hashed has its own hash left unchecked.
+pub fn check<S: Store + ?Sized>(store: &mut S, root_offset: u64) -> CheckReport {
+    let mut checker = Checker {
+        store,
+        report: CheckReport::default(),
+        visited: HashMap::new(),
+        ancestors: HashSet::new(),
+    };
+    checker.visit_ref(&NodeRef::Stored(root_offset), root_offset, &mut Nibbles::default());
+    checker.report
+}
+
+// What a parent needs to know about a checked child.
+#[derive(Clone, Copy)]
+struct Checked {
+    hash: Option<NodeHash>,
+    is_extension: bool,
+}
+
+struct Checker<'a, S: Store + ?Sized> {
+    store: &'a mut S,
+    report: CheckReport,
+    // Shared nodes are only checked once.
+    visited: HashMap<u64, Option<Checked>>,
+    ancestors: HashSet<u64>,
+}
+
+impl<S: Store + ?Sized> Checker<'_, S> {
+    fn problem(&mut self, offset: u64, path: &Nibbles, kind: ProblemKind) {
+        self.report.problems.push(Problem {
+            offset,
+            path: (0..path.len()).map(|i| path.at(i) as u8).collect(),
+            kind,
+        });
+    }
+
+    // Checks the node behind `node_ref`, whose parent's record is at
+    // `parent`. Returns `None` if it couldn't be read at all.
+    fn visit_ref(&mut self, node_ref: &NodeRef, parent: u64, path: &mut Nibbles) -> Option<Checked> {
+        match node_ref {
+            NodeRef::Stored(offset) => {
+                let offset = *offset;
+                if self.ancestors.contains(&offset) {
+                    self.problem(offset, path, ProblemKind::Cycle);
+                    return None;
+                }
+                if let Some(checked) = self.visited.get(&offset) {
+                    return *checked;
+                }
+
+                let checked = match self.store.get(offset) {
+                    Ok(node) => {
+                        self.ancestors.insert(offset);
+                        let checked = self.visit_node(&node, offset, path);
+                        self.ancestors.remove(&offset);
+                        Some(checked)
+                    }
+                    Err(e) => {
+                        self.problem(offset, path, ProblemKind::Unreadable(e.to_string()));
+                        None
+                    }
+                };
+                self.visited.insert(offset, checked);
+                checked
+            }
+            NodeRef::Inline(node) => Some(self.visit_node(node, parent, path)),
+            NodeRef::Empty | NodeRef::Dirty(_) => {
+                self.problem(parent, path, ProblemKind::Unreadable("invalid child reference".to_string()));
+                None
+            }
+        }
+    }
+
+    fn visit_node(&mut self, node: &Node, offset: u64, path: &mut Nibbles) -> Checked {
+        self.report.nodes += 1;
+
+        // Children are checked first, so their recomputed hashes can be used
+        // for this node's. They are kept at their index in the node.
+        let mut children: [Option<NodeHash>; 16] = Default::default();
+        match node {
+            Node::Leaf(_) => {}
+            Node::Extension(ext) => {
+                if ext.path.is_empty() {
+                    self.problem(offset, path, ProblemKind::EmptyExtension);
+                }
+                if ext.child.is_empty() {
+                    self.problem(offset, path, ProblemKind::ExtensionWithoutChild);
+                } else {
+                    let len = path.len();
+                    path.extend(&ext.path);
+                    let child = self.visit_ref(&ext.child, offset, path);
+                    path.truncate(len);
+
+                    if child.is_some_and(|child| child.is_extension) {
+                        self.problem(offset, path, ProblemKind::ExtensionToExtension);
+                    }
+                    children[0] = child.and_then(|child| child.hash);
+                }
+            }
+            Node::Branch(branch) => {
+                let count = branch.children.iter().filter(|child| !child.is_empty()).count();
+                if count == 0 {
+                    self.problem(offset, path, ProblemKind::ChildlessBranch);
+                } else if count == 1 && branch.value.is_none() {
+                    self.problem(offset, path, ProblemKind::SingleChildBranch);
+                }
+
+                for (nibble, child) in branch.children.iter().enumerate().filter(|(_, child)| !child.is_empty()) {
+                    path.push(nibble as u8);
+                    let checked = self.visit_ref(child, offset, path);
+                    path.truncate(path.len() - 1);
+                    children[nibble] = checked.and_then(|child| child.hash);
+                }
+            }
+        }
+
+        let computed = compute_hash(node, |i, _| children[i].ok_or("child hash unknown".into())).ok();
+
+        match (node.hash(), computed) {
+            (None, _) => self.problem(offset, path, ProblemKind::MissingHash),
+            (Some(stored), Some(computed)) if stored != computed => {
+                self.problem(offset, path, ProblemKind::HashMismatch { stored: stored.to_vec(), computed: computed.to_vec() });
+            }
+            _ => {}
+        }
+
+        Checked {
+            hash: computed,
+            is_extension: matches!(node, Node::Extension(_)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::node::{Branch, Extension, Leaf};
+    use crate::store::{FileStore, MemoryStore};
+    use crate::Trie;
+
+    use super::*;
+
+    fn kinds(report: &CheckReport) -> Vec<ProblemKind> {
+        report.problems.iter().map(|problem| problem.kind.clone()).collect()
+    }
+
+    #[test]
+    fn test_check_valid_trie() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        trie.insert(b"dog", b"puppy")?;
+        let result = trie.commit()?;
+
+        let report = check(&mut *store.borrow_mut(), result.root_offset);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert!(report.nodes >= 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_reports_bad_offsets_and_records() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-check-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let store = Rc::new(RefCell::new(FileStore::new(&path)?));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        trie.insert(b"dog", b"puppy")?;
+        let root = trie.commit()?.root_offset;
+        drop(trie);
+
+        let end = root + 2 + u16::from_be_bytes(std::fs::read(&path)?[root as usize..][..2].try_into()?) as u64;
+        // Every offset that isn't the start of a record is reported rather
+        // than decoded as whatever it lands on, and none of them panics.
+        for offset in 0..end + 8 {
+            check(&mut *store.borrow_mut(), offset);
+        }
+        for offset in root + 1..end + 8 {
+            let report = check(&mut *store.borrow_mut(), offset);
+            assert!(!report.is_ok(), "offset {}", offset);
+        }
+        drop(store);
+
+        // A record cut short by its length prefix.
+        let mut data = std::fs::read(&path)?;
+        data[root as usize..root as usize + 2].copy_from_slice(&3u16.to_be_bytes());
+        std::fs::write(&path, &data)?;
+        let mut store = FileStore::open(&path)?;
+        let report = check(&mut store, root);
+        assert!(matches!(&report.problems[..], [Problem { kind: ProblemKind::Unreadable(_), .. }]), "{:?}", report.problems);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_reports_every_problem() {
+        let mut store = MemoryStore::new();
+        let mut leaf = Node::Leaf(Leaf::new(Nibbles::from_bytes(b"a"), vec![1; 40]));
+        leaf.set_hash(NodeHash::from([0xee; 32]));
+        let leaf = store.put(leaf).unwrap();
+
+        // A branch with just the leaf, next to an empty extension over an
+        // extension, and a child that points back at the branch.
+        let mut inner = Node::Extension(Extension::new(Nibbles::from_bytes(b"b"), NodeRef::Stored(leaf)));
+        inner.set_hash(NodeHash::from([0; 32]));
+        let inner = store.put(inner).unwrap();
+        let mut outer = Node::Extension(Extension::new(Nibbles::default(), NodeRef::Stored(inner)));
+        outer.set_hash(NodeHash::from([0; 32]));
+        let outer = store.put(outer).unwrap();
+
+        let mut branch = Branch::new();
+        branch.children[1] = NodeRef::Stored(leaf);
+        let branch = store.put(Node::Branch(branch)).unwrap();
+
+        let mut root = Branch::new();
+        root.children[0] = NodeRef::Stored(branch);
+        root.children[1] = NodeRef::Stored(outer);
+        root.children[2] = NodeRef::Stored(99);
+        root.children[3] = NodeRef::Stored(5);
+        root.value = Some(b"v".to_vec());
+        let mut root = Node::Branch(root);
+        root.set_hash(NodeHash::from([0; 32]));
+        let root = store.put(root).unwrap();
+        assert_eq!(root, 4);
+        let mut cycle = Branch::new();
+        cycle.children[0] = NodeRef::Stored(root);
+        cycle.value = Some(b"v".to_vec());
+        let mut cycle = Node::Branch(cycle);
+        cycle.set_hash(NodeHash::from([0; 32]));
+        store.put(cycle).unwrap();
+
+        let report = check(&mut store, root);
+        let kinds = kinds(&report);
+        assert!(kinds.iter().any(|k| matches!(k, ProblemKind::HashMismatch { stored, .. } if stored == &[0xee; 32])));
+        assert!(kinds.contains(&ProblemKind::SingleChildBranch));
+        assert!(kinds.contains(&ProblemKind::MissingHash));
+        assert!(kinds.contains(&ProblemKind::EmptyExtension));
+        assert!(kinds.contains(&ProblemKind::ExtensionToExtension));
+        assert!(kinds.contains(&ProblemKind::Cycle));
+        assert!(kinds.iter().any(|k| matches!(k, ProblemKind::Unreadable(_))));
+
+        // The shared leaf is only checked, and reported, once.
+        let mismatches = kinds.iter().filter(|k| matches!(k, ProblemKind::HashMismatch { stored, .. } if stored == &[0xee; 32])).count();
+        assert_eq!(mismatches, 1);
+        let unreadable = report.problems.iter().find(|p| matches!(p.kind, ProblemKind::Unreadable(_))).unwrap();
+        assert_eq!(unreadable.offset, 99);
+        assert_eq!(unreadable.path, vec![2]);
+        assert!(unreadable.to_string().starts_with("offset 99 path [2]: unreadable node"));
+
+        // A branch left with nothing in it.
+        let childless = store.put(Node::Branch(Branch::new())).unwrap();
+        let report = check(&mut store, childless);
+        assert!(report.problems.iter().any(|p| p.kind == ProblemKind::ChildlessBranch), "{:?}", report.problems);
+        assert!(report.problems.iter().all(|p| p.kind != ProblemKind::SingleChildBranch));
+    }
+}
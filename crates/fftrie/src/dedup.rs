@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+
+use crate::node::{Node, NodeHash, NodeRef};
+use crate::store::{Store, SyncMode};
+use crate::view::NodeView;
+
+/// How much writing has been skipped by a `DedupStore`.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct DedupStats {
+    /// Nodes that reused an existing offset instead of being written.
+    pub hits: u64,
+    /// The encoded size of those nodes.
+    pub bytes_saved: u64,
+}
+
+/// Keeps a hash to offset index of the nodes written through it, so that a
+/// node identical to one already stored reuses its offset. Two nodes with the
+/// same hash have the same subtree below them, so either copy will do.
+///
+/// Only nodes written through the store are indexed. An offset is re-read
+/// before it's reused, so entries left stale by compaction, a rollback or an
+/// overwrite in place turn into misses. Since offsets end up shared, this
+/// shouldn't sit on top of a `PruningStore`, which would count each of them
+/// as a separate node, nor on a store that overwrites offsets in place, where
+/// two paths sharing one would overwrite each other.
+pub struct DedupStore<S: Store> {
+    store: S,
+    index: HashMap<NodeHash, u64>,
+    stats: DedupStats,
+}
+
+// Where a node of a batch ended up: at an offset that already existed, or at
+// a position in the batch that is actually written.
+enum Placement {
+    Existing(u64),
+    Batch(u32),
+}
+
+impl<S: Store> DedupStore<S> {
+    pub fn new(store: S) -> Result<Self, Box<dyn Error>> {
+        if store.overwrites_in_place() {
+            return Err("can't share offsets of a store that overwrites them".into());
+        }
+
+        Ok(Self {
+            store,
+            index: HashMap::new(),
+            stats: DedupStats::default(),
+        })
+    }
+
+    pub fn stats(&self) -> DedupStats {
+        self.stats
+    }
+
+    /// The number of distinct nodes indexed.
+    pub fn indexed(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    // The offset of a stored node with `hash`, if the one indexed still holds
+    // it. Stale entries are dropped.
+    fn lookup(&mut self, hash: Option<NodeHash>) -> Option<u64> {
+        let hash = hash?;
+        let offset = *self.index.get(&hash)?;
+        match self.store.get(offset) {
+            Ok(node) if node.hash() == Some(hash) => Some(offset),
+            _ => {
+                self.index.remove(&hash);
+                None
+            }
+        }
+    }
+
+    fn skip(&mut self, node: &Node) -> Result<(), Box<dyn Error>> {
+        // Record sizes don't depend on which offsets the children are at, so
+        // batch references can stand in for any.
+        let mut node = node.clone();
+        let children = match &mut node {
+            Node::Branch(branch) => &mut branch.children[..],
+            Node::Extension(ext) => std::slice::from_mut(&mut ext.child),
+            Node::Leaf(_) => &mut [],
+        };
+        for child in children.iter_mut().filter(|child| matches!(child, NodeRef::Dirty(_))) {
+            *child = NodeRef::Stored(0);
+        }
+        let mut record = Vec::new();
+        node.to_writer(&mut record)?;
+
+        self.stats.hits += 1;
+        self.stats.bytes_saved += record.len() as u64;
+        Ok(())
+    }
+}
+
+impl<S: Store> Store for DedupStore<S> {
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        self.store.get(offset)
+    }
+
+    fn get_many(&mut self, offsets: &[u64]) -> Result<Vec<Node>, Box<dyn Error>> {
+        self.store.get_many(offsets)
+    }
+
+    fn view(&mut self, offset: u64) -> Result<Option<NodeView<'_>>, Box<dyn Error>> {
+        self.store.view(offset)
+    }
+
+    fn get_by_hash(&mut self, hash: &[u8; 32]) -> Result<Option<(u64, Node)>, Box<dyn Error>> {
+        self.store.get_by_hash(hash)
+    }
+
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+        let hash = node.hash();
+        if let Some(offset) = self.lookup(hash) {
+            self.skip(&node)?;
+            return Ok(offset);
+        }
+
+        let offset = self.store.put(node)?;
+        if let Some(hash) = hash {
+            self.index.insert(hash, offset);
+        }
+        Ok(offset)
+    }
+
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        // Duplicates are dropped from the batch, so the references of the
+        // nodes left in it are renumbered to match.
+        let mut placements = Vec::with_capacity(nodes.len());
+        let mut batch = Vec::new();
+        let mut added: HashMap<NodeHash, u32> = HashMap::new();
+        for mut node in nodes {
+            let remap = |child: &mut NodeRef| -> Result<(), Box<dyn Error>> {
+                if let NodeRef::Dirty(index) = child {
+                    *child = match placements.get(*index as usize).ok_or("batch reference to a later node")? {
+                        Placement::Existing(offset) => NodeRef::Stored(*offset),
+                        Placement::Batch(index) => NodeRef::Dirty(*index),
+                    };
+                }
+                Ok(())
+            };
+            match &mut node {
+                Node::Branch(branch) => branch.children.iter_mut().try_for_each(remap)?,
+                Node::Extension(ext) => remap(&mut ext.child)?,
+                Node::Leaf(_) => {}
+            }
+
+            let hash = node.hash();
+            if let Some(offset) = self.lookup(hash) {
+                self.skip(&node)?;
+                placements.push(Placement::Existing(offset));
+                continue;
+            }
+            if let Some(index) = hash.and_then(|hash| added.get(&hash).copied()) {
+                self.skip(&node)?;
+                placements.push(Placement::Batch(index));
+                continue;
+            }
+
+            let index = batch.len() as u32;
+            if let Some(hash) = hash {
+                added.insert(hash, index);
+            }
+            placements.push(Placement::Batch(index));
+            batch.push(node);
+        }
+
+        let offsets = self.store.put_batch(batch)?;
+        self.index.extend(added.into_iter().map(|(hash, index)| (hash, offsets[index as usize])));
+        Ok(placements.into_iter()
+            .map(|placement| match placement {
+                Placement::Existing(offset) => offset,
+                Placement::Batch(index) => offsets[index as usize],
+            })
+            .collect())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store.flush()
+    }
+
+    fn sync_mode(&self) -> Option<SyncMode> {
+        self.store.sync_mode()
+    }
+
+    fn durable_end(&self) -> Option<u64> {
+        self.store.durable_end()
+    }
+
+    fn overwrites_in_place(&self) -> bool {
+        self.store.overwrites_in_place()
+    }
+
+    fn commit_root(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        self.store.commit_root(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::store::{FileStore, MemoryStore};
+    use crate::Trie;
+
+    use super::*;
+
+    #[test]
+    fn test_identical_subtrees_are_shared() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(DedupStore::new(MemoryStore::new())?));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+
+        // The two subtrees under 0x1 and 0x2 only differ in their first
+        // nibble, so everything below it is identical.
+        for prefix in [0x10u8, 0x20] {
+            for i in 0..4u8 {
+                trie.insert(&[prefix, i << 4], &[i; 40])?;
+            }
+        }
+        let first = trie.commit()?;
+
+        // The second subtree's 4 leaves, branch and extension are all
+        // written once.
+        let stats = store.borrow().stats();
+        assert_eq!(stats.hits, 6);
+        assert!(stats.bytes_saved > 4 * 40);
+
+        let mut trie = Trie::new(Rc::clone(&store), Some(first.root_offset));
+        assert_eq!(trie.get(&[0x20, 0x30])?, vec![3; 40]);
+        assert_eq!(trie.iter().count(), 8);
+        assert_eq!(trie.calculate_root()?, first.root_hash);
+
+        // A later commit that recreates the subtree under 0x3 reuses the
+        // stored one.
+        for i in 0..4u8 {
+            trie.insert(&[0x30, i << 4], &[i; 40])?;
+        }
+        trie.commit()?;
+        assert_eq!(store.borrow().stats().hits, 12);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_offsets_are_not_reused() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-dedup-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let store = Rc::new(RefCell::new(DedupStore::new(FileStore::new(&path)?)?));
+
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for i in 0..8u8 {
+            trie.insert(&[i], &[i; 40])?;
+        }
+        trie.commit()?;
+        trie.insert(&[0], &[9; 40])?;
+        let root = trie.commit()?.root_offset;
+        drop(trie);
+
+        // Compaction moves every node, so the offset indexed for the leaf
+        // that was overwritten above no longer holds it.
+        let root = store.borrow_mut().inner_mut().compact(&[root])?[0];
+        let mut trie = Trie::new(Rc::clone(&store), Some(root));
+        trie.insert(&[0], &[0; 40])?;
+        let result = trie.commit()?;
+
+        let mut trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+        assert_eq!(trie.get(&[0])?, vec![0; 40]);
+        assert_eq!(trie.calculate_root()?, result.root_hash);
+        let report = crate::check(store.borrow_mut().inner_mut(), result.root_offset);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        std::fs::remove_file(&path)?;
+
+        // A store that overwrites offsets in place can't share them.
+        let path = format!("{}.paths", path);
+        assert!(DedupStore::new(crate::pathdb::PathStore::new(&path, b"", 0)?).is_err());
+        std::fs::remove_file(format!("{}.journal", path))?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::rc::Rc;
+
+use crate::iter::TrieIter;
+use crate::nibbles::Nibbles;
+use crate::node::NodeRef;
+use crate::store::{MemoryStore, Store};
+use crate::{Trie, EMPTY_ROOT_HASH};
+
+/// A flat copy of every key/value in a committed trie, so that point reads
+/// take one lookup instead of a walk from the root. Once attached to a trie,
+/// it is kept in sync at each commit.
+///
+/// The layer is fresh when it matches the root hash the trie was last
+/// committed or opened at. Hashes are compared rather than offsets, which a
+/// store can hand out again after compacting. A stale layer is never read
+/// from; it can be brought back in line with `Trie::rebuild_flat`.
+pub struct FlatState {
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+    // Writes since the last commit, applied to `entries` when it lands. Removed
+    // keys are `None`.
+    pending: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    root: [u8; 32],
+    rebuild: Option<Rebuild>,
+}
+
+// A rebuild in progress, walking the trie at `root` a few entries at a time.
+struct Rebuild {
+    root: [u8; 32],
+    stack: Vec<(NodeRef, Nibbles)>,
+    entries: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Default for FlatState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlatState {
+    /// A layer for an empty trie.
+    pub fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            root: EMPTY_ROOT_HASH,
+            rebuild: None,
+        }
+    }
+
+    /// The root hash the entries were last in sync with.
+    pub fn root(&self) -> [u8; 32] {
+        self.root
+    }
+
+    pub fn len(&self) -> usize {
+        let mut len = self.entries.len();
+        for (key, value) in &self.pending {
+            match (self.entries.contains_key(key), value) {
+                (false, Some(_)) => len += 1,
+                (true, None) => len -= 1,
+                _ => {}
+            }
+        }
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        match self.pending.get(key) {
+            Some(value) => value.as_deref(),
+            None => self.entries.get(key).map(Vec::as_slice),
+        }
+    }
+
+    pub fn is_rebuilding(&self) -> bool {
+        self.rebuild.is_some()
+    }
+
+    /// Checks the entries, including uncommitted writes, against `root_hash`
+    /// by hashing a scratch trie built from them.
+    pub fn verify(&self, root_hash: [u8; 32]) -> Result<bool, Box<dyn Error>> {
+        let mut trie = Trie::new_empty(Rc::new(RefCell::new(MemoryStore::new())));
+        for (key, value) in &self.entries {
+            trie.insert(key, value)?;
+        }
+        for (key, value) in &self.pending {
+            match value {
+                Some(value) => trie.insert(key, value)?,
+                None => _ = trie.remove(key)?,
+            }
+        }
+        Ok(trie.calculate_root()? == root_hash)
+    }
+
+    pub(crate) fn is_fresh(&self, root: Option<[u8; 32]>) -> bool {
+        self.rebuild.is_none() && Some(self.root) == root
+    }
+
+    pub(crate) fn record(&mut self, key: &[u8], value: &[u8]) {
+        self.pending.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub(crate) fn record_removal(&mut self, key: &[u8]) {
+        self.pending.insert(key.to_vec(), None);
+    }
+
+    // Moves the trie from `base` to `root`. Pending writes only carry over if
+    // the entries were in sync with `base`; otherwise a rebuild picks them up.
+    pub(crate) fn commit(&mut self, base: Option<[u8; 32]>, root: [u8; 32]) {
+        let pending = std::mem::take(&mut self.pending);
+        if self.is_fresh(base) {
+            for (key, value) in pending {
+                match value {
+                    Some(value) => self.entries.insert(key, value),
+                    None => self.entries.remove(&key),
+                };
+            }
+            self.root = root;
+        }
+    }
+
+    // Reads up to `limit` more entries of the trie's committed root into a
+    // rebuild, starting over if the trie has moved on since it began. The
+    // old entries stay in place until the rebuild finishes.
+    pub(crate) fn rebuild<S: Store + ?Sized>(&mut self, trie: &Trie<S>, limit: usize) -> Result<bool, Box<dyn Error>> {
+        let root = trie.base_hash.ok_or("root hash is not known")?;
+        if self.is_fresh(Some(root)) {
+            return Ok(true);
+        }
+
+        let mut rebuild = match self.rebuild.take() {
+            Some(rebuild) if rebuild.root == root => rebuild,
+            _ => Rebuild {
+                root,
+                stack: trie.base.map(|offset| vec![(NodeRef::Stored(offset), Nibbles::default())]).unwrap_or_default(),
+                entries: BTreeMap::new(),
+            },
+        };
+
+        let mut iter = TrieIter::from_stack(trie, rebuild.stack);
+        for entry in iter.by_ref().take(limit) {
+            let (key, value) = entry?;
+            rebuild.entries.insert(key, value);
+        }
+
+        rebuild.stack = iter.into_stack();
+        if !rebuild.stack.is_empty() {
+            self.rebuild = Some(rebuild);
+            return Ok(false);
+        }
+
+        self.entries = rebuild.entries;
+        self.root = root;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::Node;
+
+    use super::*;
+
+    #[test]
+    fn test_flat_state() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.attach_flat(FlatState::new())?;
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        let first = trie.commit()?;
+
+        // Kept in sync by the commit.
+        let flat = trie.flat().unwrap();
+        assert_eq!(flat.root(), first.root_hash);
+        assert_eq!(flat.get(b"doge"), Some(&b"coin"[..]));
+        assert_eq!(flat.len(), 3);
+        assert!(trie.verify_flat()?);
+
+        // Uncommitted writes are read back from the layer as well.
+        trie.insert(b"dog", b"puppy")?;
+        assert_eq!(trie.get(b"dog")?, b"puppy");
+        assert!(trie.verify_flat()?);
+        let second = trie.commit()?;
+        assert!(trie.flat().unwrap().verify(second.root_hash)?);
+        assert!(!trie.flat().unwrap().verify(first.root_hash)?);
+
+        // So are removals.
+        trie.remove(b"dog")?;
+        assert!(trie.get(b"dog").is_err());
+        assert_eq!(trie.flat().unwrap().len(), 3);
+        assert!(trie.verify_flat()?);
+        trie.commit()?;
+        assert_eq!(trie.flat().unwrap().get(b"dog"), None);
+        trie.insert(b"dog", b"puppy")?;
+        assert_eq!(trie.commit()?.root_hash, second.root_hash);
+
+        // A layer from another root is stale, so reads go to the trie until
+        // a rebuild catches it up.
+        let mut trie = Trie::new(Rc::clone(&store), Some(second.root_offset));
+        trie.attach_flat(FlatState::new())?;
+        assert!(trie.verify_flat().is_err());
+        assert_eq!(trie.get(b"horse")?, b"stallion");
+        assert!(!trie.rebuild_flat(3)?);
+        assert!(trie.flat().unwrap().is_rebuilding());
+        assert!(trie.get(b"cat").is_err());
+        assert!(trie.rebuild_flat(3)?);
+        assert_eq!(trie.flat().unwrap().len(), 4);
+        assert!(trie.verify_flat()?);
+        assert!(trie.get(b"cat").is_err());
+
+        // Attaching is refused while there are uncommitted writes, since the
+        // layer would miss them.
+        let mut detached = trie.detach_flat().unwrap();
+        trie.insert(b"cat", b"meow")?;
+        assert!(trie.attach_flat(FlatState::new()).is_err());
+        detached.record(b"cat", b"meow");
+        assert!(detached.verify(trie.calculate_root()?)?);
+        Ok(())
+    }
+
+    // Fails every read once `fail` is set.
+    #[derive(Default)]
+    struct FailingStore {
+        store: MemoryStore,
+        fail: bool,
+    }
+
+    impl Store for FailingStore {
+        fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+            if self.fail {
+                return Err("read failed".into());
+            }
+            self.store.get(offset)
+        }
+
+        fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+            self.store.put(node)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.store.flush()
+        }
+    }
+
+    #[test]
+    fn test_flat_state_follows_root_hash() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(FailingStore::default()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.attach_flat(FlatState::new())?;
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        let result = trie.commit()?;
+
+        // A failed insert leaves the layer as it was.
+        store.borrow_mut().fail = true;
+        assert!(trie.insert(b"dog", b"puppy").is_err());
+        assert_eq!(trie.flat().unwrap().get(b"dog"), None);
+        assert_eq!(trie.flat().unwrap().len(), 2);
+        store.borrow_mut().fail = false;
+
+        // Another trie whose root has the same offset, as a compacted store
+        // could hand out, doesn't read from the layer.
+        let flat = trie.detach_flat().unwrap();
+        let other = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&other));
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"pony")?;
+        let moved = trie.commit()?;
+        assert_eq!(moved.root_offset, result.root_offset);
+
+        let mut trie = Trie::new(other, Some(moved.root_offset));
+        trie.attach_flat(flat)?;
+        assert_eq!(trie.get(b"horse")?, b"pony");
+        assert!(trie.verify_flat().is_err());
+        assert!(trie.rebuild_flat(10)?);
+        assert_eq!(trie.flat().unwrap().root(), moved.root_hash);
+        assert_eq!(trie.get(b"horse")?, b"pony");
+        Ok(())
+    }
+}
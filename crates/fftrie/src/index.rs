@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Read, Write};
+
+use crate::node::{Node, NodeRef};
+use crate::root_hash;
+use crate::store::{Store, SyncMode};
+use crate::view::NodeView;
+
+// Each entry in an index file is a hash followed by a big-endian offset.
+const ENTRY_SIZE: usize = 32 + 8;
+
+/// Maps the hash of every node written through it to the node's offset, so
+/// that nodes can be found with `get_by_hash`. Nodes are indexed by the
+/// keccak of their encoding, even when that is short enough to be embedded,
+/// so a root can be looked up by the hash recorded in a header.
+///
+/// An index opened with `open` is kept in a file next to the store. Entries
+/// are appended to it at each commit, before the store records the commit,
+/// and synced as the store's `SyncMode` asks. Entries past the store's last
+/// durable commit are dropped on open, and a lookup checks the node it finds,
+/// so entries for offsets the store has since reused are misses. Compaction
+/// moves every node, so the index has to be rebuilt from the new roots with
+/// `rebuild` afterwards.
+pub struct HashIndexStore<S: Store> {
+    store: S,
+    index: HashMap<[u8; 32], u64>,
+    file: Option<File>,
+    // Entries not yet appended to the file.
+    pending: Vec<([u8; 32], u64)>,
+}
+
+impl<S: Store> HashIndexStore<S> {
+    /// An index held only in memory.
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            index: HashMap::new(),
+            file: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Loads the index file at `path`, creating it if it doesn't exist.
+    pub fn open(store: S, path: &str) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        // A torn final entry is dropped, and so are entries for writes the
+        // store rolled back when it was opened.
+        let end = store.durable_end().unwrap_or(u64::MAX);
+        let entries: Vec<([u8; 32], u64)> = data.chunks_exact(ENTRY_SIZE)
+            .map(|entry| {
+                let hash: [u8; 32] = entry[..32].try_into().unwrap();
+                (hash, u64::from_be_bytes(entry[32..].try_into().unwrap()))
+            })
+            .filter(|(_, offset)| *offset < end)
+            .collect();
+
+        if entries.len() * ENTRY_SIZE != data.len() {
+            rewrite(&mut file, &entries)?;
+        }
+        let index = entries.into_iter().collect();
+
+        Ok(Self {
+            store,
+            index,
+            file: Some(file),
+            pending: Vec::new(),
+        })
+    }
+
+    /// The number of hashes indexed.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Replaces the index with the nodes reachable from `roots`, as is needed
+    /// once the store has been compacted.
+    pub fn rebuild(&mut self, roots: &[u64]) -> Result<(), Box<dyn Error>> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        let mut stack = roots.to_vec();
+        while let Some(offset) = stack.pop() {
+            if !seen.insert(offset) {
+                continue;
+            }
+            let node = self.store.get(offset)?;
+            let children = match &node {
+                Node::Branch(branch) => &branch.children[..],
+                Node::Extension(ext) => std::slice::from_ref(&ext.child),
+                Node::Leaf(_) => &[],
+            };
+            stack.extend(children.iter().filter_map(|child| match child {
+                NodeRef::Stored(offset) => Some(*offset),
+                _ => None,
+            }));
+            if let Some(node_hash) = node.hash() {
+                entries.push((root_hash(&node_hash), offset));
+            }
+        }
+
+        if let Some(file) = &mut self.file {
+            rewrite(file, &entries)?;
+        }
+        self.pending.clear();
+        self.index = entries.into_iter().collect();
+        Ok(())
+    }
+
+    fn add(&mut self, node_hash: Option<&[u8]>, offset: u64) {
+        if let Some(node_hash) = node_hash {
+            let hash = root_hash(node_hash);
+            self.index.insert(hash, offset);
+            if self.file.is_some() {
+                self.pending.push((hash, offset));
+            }
+        }
+    }
+}
+
+// Replaces the contents of an index file with `entries`.
+fn rewrite(file: &mut File, entries: &[([u8; 32], u64)]) -> io::Result<()> {
+    file.set_len(0)?;
+    let mut writer = BufWriter::new(&mut *file);
+    for (hash, offset) in entries {
+        writer.write_all(hash)?;
+        writer.write_all(&offset.to_be_bytes())?;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_data()
+}
+
+impl<S: Store> Store for HashIndexStore<S> {
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        self.store.get(offset)
+    }
+
+    fn get_many(&mut self, offsets: &[u64]) -> Result<Vec<Node>, Box<dyn Error>> {
+        self.store.get_many(offsets)
+    }
+
+    fn view(&mut self, offset: u64) -> Result<Option<NodeView<'_>>, Box<dyn Error>> {
+        self.store.view(offset)
+    }
+
+    fn get_by_hash(&mut self, hash: &[u8; 32]) -> Result<Option<(u64, Node)>, Box<dyn Error>> {
+        let Some(offset) = self.index.get(hash).copied() else {
+            return Ok(None);
+        };
+
+        match self.store.get(offset) {
+            Ok(node) if node.hash().is_some_and(|node_hash| root_hash(&node_hash) == *hash) => Ok(Some((offset, node))),
+            _ => Ok(None),
+        }
+    }
+
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+        let hash = node.hash();
+        let offset = self.store.put(node)?;
+        self.add(hash.as_deref(), offset);
+        Ok(offset)
+    }
+
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        let hashes: Vec<_> = nodes.iter().map(Node::hash).collect();
+        let offsets = self.store.put_batch(nodes)?;
+        for (hash, offset) in hashes.iter().zip(&offsets) {
+            self.add(hash.as_deref(), *offset);
+        }
+        Ok(offsets)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store.flush()
+    }
+
+    fn sync_mode(&self) -> Option<SyncMode> {
+        self.store.sync_mode()
+    }
+
+    fn durable_end(&self) -> Option<u64> {
+        self.store.durable_end()
+    }
+
+    fn overwrites_in_place(&self) -> bool {
+        self.store.overwrites_in_place()
+    }
+
+    // The entries are written first, so that a commit the store records is
+    // never missing from the index.
+    fn commit_root(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        if let Some(file) = &mut self.file {
+            let mut writer = BufWriter::new(&mut *file);
+            for (hash, offset) in self.pending.drain(..) {
+                writer.write_all(&hash)?;
+                writer.write_all(&offset.to_be_bytes())?;
+            }
+            writer.flush()?;
+            drop(writer);
+            if self.store.sync_mode() == Some(SyncMode::Fsync) {
+                file.sync_data()?;
+            }
+        }
+
+        self.store.commit_root(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::store::{FileStore, SyncMode};
+    use crate::Trie;
+
+    use super::*;
+
+    #[test]
+    fn test_lookup_by_hash() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-index-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let index_path = format!("{}.idx", path);
+
+        let store = HashIndexStore::open(FileStore::new(&path)?, &index_path)?;
+        let store = Rc::new(RefCell::new(store));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.insert(b"do", b"verb")?;
+        let small = trie.commit()?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        let result = trie.commit()?;
+        assert!(store.borrow_mut().get_by_hash(&[0; 32])?.is_none());
+        drop(trie);
+        drop(store);
+
+        // The index survives reopening, and tries can be opened by root hash,
+        // including one whose root is short enough to be embedded.
+        let store = HashIndexStore::open(FileStore::open(&path)?, &index_path)?;
+        let store = Rc::new(RefCell::new(store));
+        assert!(store.borrow().len() >= 3);
+        let (offset, _) = store.borrow_mut().get_by_hash(&result.root_hash)?.unwrap();
+        assert_eq!(offset, result.root_offset);
+
+        let trie = Trie::from_root_hash(Rc::clone(&store), &result.root_hash)?;
+        assert_eq!(trie.get(b"doge")?, b"coin");
+        let trie = Trie::from_root_hash(Rc::clone(&store), &small.root_hash)?;
+        assert_eq!(trie.get(b"do")?, b"verb");
+        assert!(trie.get(b"horse").is_err());
+        assert!(Trie::from_root_hash(Rc::clone(&store), &[1; 32]).is_err());
+
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&index_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_entries_are_misses() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-index-stale-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let index_path = format!("{}.idx", path);
+
+        let store = HashIndexStore::open(FileStore::new(&path)?, &index_path)?;
+        let store = Rc::new(RefCell::new(store));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        let durable = trie.commit()?;
+        let indexed = store.borrow().len();
+
+        // A commit the store rolls back on reopening.
+        store.borrow_mut().inner_mut().set_sync_mode(SyncMode::None);
+        trie.insert(b"doge", b"coin")?;
+        let lost = trie.commit()?;
+        drop(trie);
+        drop(store);
+
+        // An entry pointing into the middle of a record.
+        let mut entry = [7; 32].to_vec();
+        entry.extend_from_slice(&(durable.root_offset + 2).to_be_bytes());
+        let mut file = OpenOptions::new().append(true).open(&index_path)?;
+        file.write_all(&entry)?;
+        drop(file);
+
+        let store = HashIndexStore::open(FileStore::open(&path)?, &index_path)?;
+        let store = Rc::new(RefCell::new(store));
+        assert_eq!(store.borrow().len(), indexed + 1);
+        assert_eq!(std::fs::metadata(&index_path)?.len(), ((indexed + 1) * ENTRY_SIZE) as u64);
+        assert!(store.borrow_mut().get_by_hash(&lost.root_hash)?.is_none());
+        assert!(store.borrow_mut().get_by_hash(&[7; 32])?.is_none());
+
+        // The rolled back offsets are reused by the next commit, which the
+        // index finds.
+        let mut trie = Trie::new(Rc::clone(&store), Some(durable.root_offset));
+        trie.insert(b"dog", b"puppy")?;
+        let result = trie.commit()?;
+        let (offset, _) = store.borrow_mut().get_by_hash(&result.root_hash)?.unwrap();
+        assert_eq!(offset, result.root_offset);
+        assert!(store.borrow_mut().get_by_hash(&lost.root_hash)?.is_none());
+
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&index_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rebuild_after_compaction() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-index-compact-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let index_path = format!("{}.idx", path);
+
+        let store = HashIndexStore::open(FileStore::new(&path)?, &index_path)?;
+        let store = Rc::new(RefCell::new(store));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for i in 0..8u8 {
+            trie.insert(&[i], &[i; 40])?;
+        }
+        let old = trie.commit()?;
+        trie.insert(&[0], &[9; 40])?;
+        let result = trie.commit()?;
+        drop(trie);
+
+        let roots = store.borrow_mut().inner_mut().compact(&[result.root_offset])?;
+        store.borrow_mut().rebuild(&roots)?;
+        assert!(store.borrow_mut().get_by_hash(&old.root_hash)?.is_none());
+        let (offset, _) = store.borrow_mut().get_by_hash(&result.root_hash)?.unwrap();
+        assert_eq!(offset, roots[0]);
+        let indexed = store.borrow().len();
+        drop(store);
+
+        // The rewritten file only has the entries for the retained root.
+        let store = HashIndexStore::open(FileStore::open(&path)?, &index_path)?;
+        let store = Rc::new(RefCell::new(store));
+        assert_eq!(store.borrow().len(), indexed);
+        let trie = Trie::from_root_hash(Rc::clone(&store), &result.root_hash)?;
+        assert_eq!(trie.get(&[0])?, vec![9; 40]);
+
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&index_path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::error::Error;
+
+use crate::nibbles::Nibbles;
+use crate::node::{Node, NodeRef};
+use crate::store::Store;
+use crate::Trie;
+
+/// A key and its value, as yielded by [`TrieIter`].
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+/// Walks a trie depth-first and yields every key/value pair in key order.
+pub struct TrieIter<'a, S: Store + ?Sized = dyn Store> {
+    trie: &'a Trie<S>,
+    stack: Vec<(NodeRef, Nibbles)>,
+}
+
+impl<'a, S: Store + ?Sized> TrieIter<'a, S> {
+    pub(crate) fn new(trie: &'a Trie<S>, root: NodeRef) -> Self {
+        let stack = if root.is_empty() { Vec::new() } else { vec![(root, Nibbles::default())] };
+        Self::from_stack(trie, stack)
+    }
+
+    // Picks up a walk saved by `into_stack`. The stack must only hold stored
+    // or inline refs if the trie has been changed in between.
+    pub(crate) fn from_stack(trie: &'a Trie<S>, stack: Vec<(NodeRef, Nibbles)>) -> Self {
+        Self {
+            trie,
+            stack,
+        }
+    }
+
+    // The nodes still to be visited, each with the path leading to it.
+    pub(crate) fn into_stack(self) -> Vec<(NodeRef, Nibbles)> {
+        self.stack
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>, Box<dyn Error>> {
+        while let Some((node_ref, mut prefix)) = self.stack.pop() {
+            match self.trie.get_node(&node_ref)? {
+                Node::Leaf(leaf) => {
+                    prefix.extend(&leaf.path);
+                    return Ok(Some((prefix.to_bytes()?, leaf.value)));
+                }
+                Node::Extension(ext) => {
+                    prefix.extend(&ext.path);
+                    self.stack.push((ext.child, prefix));
+                }
+                Node::Branch(branch) => {
+                    // Push children in reverse so that the lowest nibble is popped first.
+                    for (i, child) in branch.children.into_iter().enumerate().rev() {
+                        if child.is_empty() {
+                            continue;
+                        }
+
+                        let mut child_prefix = prefix.clone();
+                        child_prefix.push(i as u8);
+                        self.stack.push((child, child_prefix));
+                    }
+
+                    // The branch's own value sorts before everything below it.
+                    if let Some(value) = branch.value {
+                        return Ok(Some((prefix.to_bytes()?, value)));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<S: Store + ?Sized> Iterator for TrieIter<'_, S> {
+    type Item = Result<Entry, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                self.stack.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+
+use crate::store::Store;
+use crate::Trie;
+
+// The state after one block on top of the state at `parent`: the disk trie
+// with the changes of every layer down to the disk layer applied in its
+// arena. The layer's own root hash is its key in the stack.
+struct DiffLayer<S: Store + ?Sized> {
+    parent: [u8; 32],
+    trie: Trie<S>,
+}
+
+/// Recent states kept as in-memory diff layers over a single persisted disk
+/// layer. Each layer is addressed by its root hash and may have siblings, so
+/// that a reorg is just an update from an older parent. Once a chain of
+/// layers grows deeper than `depth`, its bottom layer is written to the store
+/// and becomes the new disk layer; forks that branched off below it are
+/// dropped.
+pub struct LayerStack<S: Store + ?Sized = dyn Store> {
+    depth: usize,
+    disk: Trie<S>,
+    disk_hash: [u8; 32],
+    layers: HashMap<[u8; 32], DiffLayer<S>>,
+}
+
+impl<S: Store + ?Sized> LayerStack<S> {
+    /// Stacks layers over the trie committed at `root_offset`.
+    pub fn new(store: Rc<RefCell<S>>, root_offset: Option<u64>, depth: usize) -> Result<Self, Box<dyn Error>> {
+        let mut disk = Trie::new(store, root_offset);
+        let disk_hash = disk.calculate_root()?;
+        Ok(Self {
+            depth,
+            disk,
+            disk_hash,
+            layers: HashMap::new(),
+        })
+    }
+
+    /// The offset of the persisted root all layers sit on.
+    pub fn disk_root(&self) -> Option<u64> {
+        self.disk.root_offset()
+    }
+
+    pub fn disk_hash(&self) -> [u8; 32] {
+        self.disk_hash
+    }
+
+    /// The number of diff layers held in memory, across all forks.
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Whether the state at `root` can still be read.
+    pub fn contains(&self, root: &[u8; 32]) -> bool {
+        *root == self.disk_hash || self.layers.contains_key(root)
+    }
+
+    /// Adds a layer holding `changes` on top of the state at `parent` and
+    /// returns its root hash. A change to `None` deletes the key. Writes
+    /// nothing to the store unless the new layer pushes its chain past the
+    /// depth limit.
+    pub fn update(&mut self, parent: [u8; 32], changes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<[u8; 32], Box<dyn Error>> {
+        // The new layer starts from a copy of its parent's trie, so only its
+        // own changes are applied and hashed.
+        let mut trie = self.trie(&parent)?.fork();
+        for (key, value) in &changes {
+            match value {
+                Some(value) => trie.insert(key, value)?,
+                None => {
+                    trie.remove(key)?;
+                }
+            }
+        }
+
+        let root = trie.calculate_root()?;
+        if !self.contains(&root) {
+            self.layers.insert(root, DiffLayer { parent, trie });
+        }
+
+        // An update that lands on a state already held, such as one that
+        // changes nothing, is measured by that state's own chain.
+        let chain = self.chain(&root)?;
+        if chain.len() > self.depth {
+            self.flatten(*chain.last().unwrap())?;
+        }
+
+        Ok(root)
+    }
+
+    /// Reads `key` in the state at `root`. A key missing from that state is
+    /// `None`; a state that isn't held is an error.
+    pub fn get(&self, root: &[u8; 32], key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        self.trie(root)?.find_with(key, <[u8]>::to_vec)
+    }
+
+    fn trie(&self, root: &[u8; 32]) -> Result<&Trie<S>, Box<dyn Error>> {
+        if *root == self.disk_hash {
+            return Ok(&self.disk);
+        }
+        Ok(&self.layers.get(root).ok_or("unknown state root")?.trie)
+    }
+
+    // The layers from `root` down to the disk layer, top first.
+    fn chain(&self, root: &[u8; 32]) -> Result<Vec<[u8; 32]>, Box<dyn Error>> {
+        let mut chain = Vec::new();
+        let mut current = *root;
+        while current != self.disk_hash {
+            let layer = self.layers.get(&current).ok_or("unknown state root")?;
+            chain.push(current);
+            current = layer.parent;
+        }
+        Ok(chain)
+    }
+
+    // Writes the layer at `root`, which must sit directly on the disk layer,
+    // into the store and makes it the new disk layer.
+    fn flatten(&mut self, root: [u8; 32]) -> Result<(), Box<dyn Error>> {
+        let layer = self.layers.remove(&root).ok_or("unknown state root")?;
+        if layer.parent != self.disk_hash {
+            return Err("layer is not on the disk layer".into());
+        }
+
+        // A layer that deleted every key has nothing to write.
+        let mut trie = layer.trie;
+        let written = match trie.root_offset() {
+            None if trie.calculate_root()? == crate::EMPTY_ROOT_HASH => None,
+            _ => {
+                let (result, written) = trie.commit_written()?;
+                if result.root_hash != root {
+                    return Err("flattened root does not match the layer".into());
+                }
+                Some(written)
+            }
+        };
+        self.disk = trie;
+        self.disk_hash = root;
+
+        // Forks below the new disk layer can no longer be reached. The layers
+        // left all sit on it, and hold copies of the nodes just written.
+        let live: Vec<[u8; 32]> = self.layers.keys().filter(|layer| self.chain(layer).is_ok()).copied().collect();
+        self.layers.retain(|layer, _| live.contains(layer));
+        if let Some(written) = written {
+            for layer in self.layers.values_mut() {
+                layer.trie.rebase(&written);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::node::Node;
+    use crate::store::MemoryStore;
+
+    use super::*;
+
+    fn changes(pairs: &[(&[u8], &[u8])]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        pairs.iter().map(|(key, value)| (key.to_vec(), Some(value.to_vec()))).collect()
+    }
+
+    // Counts the nodes written through it.
+    #[derive(Default)]
+    struct CountingStore {
+        store: MemoryStore,
+        puts: usize,
+    }
+
+    impl Store for CountingStore {
+        fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+            self.store.get(offset)
+        }
+
+        fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+            self.puts += 1;
+            self.store.put(node)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.store.flush()
+        }
+    }
+
+    #[test]
+    fn test_layers() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        let base = trie.commit()?;
+
+        let mut stack = LayerStack::new(Rc::clone(&store), Some(base.root_offset), 2)?;
+        assert_eq!(stack.disk_hash(), base.root_hash);
+
+        let a = stack.update(base.root_hash, changes(&[(b"doge", b"coin")]))?;
+        let b = stack.update(a, changes(&[(b"dog", b"puppy"), (b"do", b"noun")]))?;
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.disk_root(), Some(base.root_offset));
+
+        // Every recent state reads through to the disk layer.
+        assert_eq!(stack.get(&b, b"do")?.unwrap(), b"noun");
+        assert_eq!(stack.get(&a, b"do")?.unwrap(), b"verb");
+        assert_eq!(stack.get(&b, b"horse")?.unwrap(), b"stallion");
+        assert_eq!(stack.get(&a, b"dog")?, None);
+        assert!(stack.get(&[0; 32], b"do").is_err());
+
+        // A reorg replaces `b` with a sibling built on `a`.
+        let b2 = stack.update(a, changes(&[(b"dog", b"hound")]))?;
+        assert_eq!(stack.get(&b2, b"dog")?.unwrap(), b"hound");
+        assert_eq!(stack.get(&b, b"dog")?.unwrap(), b"puppy");
+        assert_eq!(stack.len(), 3);
+
+        // Going past the depth limit flattens `a` into the store. `b` is
+        // still on top of it and survives.
+        let c = stack.update(b2, changes(&[(b"cat", b"meow")]))?;
+        assert_eq!(stack.disk_hash(), a);
+        assert!(stack.disk_root() != Some(base.root_offset));
+        assert!(stack.contains(&b));
+        assert!(!stack.contains(&base.root_hash));
+        assert_eq!(stack.len(), 3);
+
+        let mut expected = Trie::new_empty(Rc::new(RefCell::new(MemoryStore::new())));
+        for (key, value) in [(&b"do"[..], &b"verb"[..]), (b"horse", b"stallion"), (b"doge", b"coin"), (b"dog", b"hound"), (b"cat", b"meow")] {
+            expected.insert(key, value)?;
+        }
+        assert_eq!(expected.calculate_root()?, c);
+        assert_eq!(stack.get(&c, b"doge")?.unwrap(), b"coin");
+
+        // Flattening `b2` drops the `b` fork.
+        stack.update(c, changes(&[(b"cow", b"moo")]))?;
+        assert_eq!(stack.disk_hash(), b2);
+        assert!(!stack.contains(&b));
+        assert_eq!(stack.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_layers_without_depth() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut stack = LayerStack::new(Rc::clone(&store), None, 0)?;
+
+        // An empty disk layer reads as missing keys.
+        let empty = stack.disk_hash();
+        assert_eq!(stack.get(&empty, b"do")?, None);
+
+        // Updates are flattened straight away, and one that changes nothing
+        // leaves the disk layer where it is.
+        assert_eq!(stack.update(empty, Vec::new())?, empty);
+        assert_eq!(stack.disk_root(), None);
+        let a = stack.update(empty, changes(&[(b"do", b"verb")]))?;
+        assert_eq!(stack.disk_hash(), a);
+        assert!(stack.is_empty());
+        let offset = stack.disk_root();
+        assert_eq!(stack.update(a, changes(&[(b"do", b"verb")]))?, a);
+        assert_eq!(stack.disk_root(), offset);
+        assert_eq!(stack.get(&a, b"do")?.unwrap(), b"verb");
+        assert_eq!(stack.get(&a, b"dog")?, None);
+        assert!(stack.get(&[0; 32], b"do").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_layers_with_deletes() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(CountingStore::default()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for i in 0..16u8 {
+            trie.insert(&[i], &[i; 40])?;
+        }
+        let base = trie.commit()?;
+
+        let mut stack = LayerStack::new(Rc::clone(&store), Some(base.root_offset), 1)?;
+        let a = stack.update(base.root_hash, vec![(vec![1], None), (vec![2], Some(vec![9; 40]))])?;
+        assert_eq!(stack.get(&a, &[1])?, None);
+        assert_eq!(stack.get(&a, &[2])?, Some(vec![9; 40]));
+        assert_eq!(stack.get(&base.root_hash, &[1])?, Some(vec![1; 40]));
+
+        // Flattening `a` writes its leaf and the extension and branch above
+        // it. Flattening `b` after it doesn't write `a`'s leaf again.
+        let puts = store.borrow().puts;
+        let b = stack.update(a, vec![(vec![3], Some(vec![8; 40]))])?;
+        assert_eq!(stack.disk_hash(), a);
+        assert_eq!(store.borrow().puts - puts, 3);
+        let puts = store.borrow().puts;
+        let c = stack.update(b, vec![(vec![3], None), (vec![4], None)])?;
+        assert_eq!(stack.disk_hash(), b);
+        assert_eq!(store.borrow().puts - puts, 3);
+
+        let mut expected = Trie::new_empty(Rc::new(RefCell::new(MemoryStore::new())));
+        for i in (0..16u8).filter(|i| ![1, 3, 4].contains(i)) {
+            expected.insert(&[i], &[if i == 2 { 9 } else { i }; 40])?;
+        }
+        assert_eq!(expected.calculate_root()?, c);
+        assert_eq!(stack.get(&c, &[2])?, Some(vec![9; 40]));
+        assert_eq!(stack.get(&c, &[3])?, None);
+
+        // Deleting every key leaves an empty disk layer.
+        let keys: Vec<_> = (0..16u8).filter(|i| ![1, 3, 4].contains(i)).map(|i| (vec![i], None)).collect();
+        let empty = stack.update(c, keys)?;
+        stack.update(empty, changes(&[(b"do", b"verb")]))?;
+        assert_eq!(stack.disk_hash(), empty);
+        assert_eq!(stack.disk_root(), None);
+        assert_eq!(stack.get(&empty, &[2])?, None);
+        Ok(())
+    }
+}
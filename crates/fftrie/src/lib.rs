@@ -6,13 +6,35 @@ use std::rc::Rc;
 use rlp::RlpStream;
 use tiny_keccak::Hasher;
 
-use crate::nibbles::Nibbles;
-use crate::node::{Branch, Extension, Leaf, Node};
+use crate::flat::FlatState;
+use crate::iter::TrieIter;
+use crate::nibbles::{NibbleSlice, Nibbles};
+use crate::node::{Branch, Extension, Leaf, Node, NodeHash, NodeRef};
 use crate::store::Store;
-
+use crate::view::{ChildView, NodeView};
+
+pub use crate::check::{check, CheckReport, Problem, ProblemKind};
+pub use crate::stats::{NodeTypeStats, TrieStats};
+pub use crate::visualize::DumpOptions;
+
+mod cache;
+mod check;
+pub mod dedup;
+pub mod flat;
+pub mod index;
+mod iter;
+pub mod layers;
 mod nibbles;
 mod node;
-mod store;
+pub mod pathdb;
+pub mod pin;
+pub mod prune;
+pub mod snapshot;
+mod stats;
+pub mod store;
+mod superblock;
+mod view;
+mod visualize;
 
 const EMPTY_ROOT_HASH: [u8; 32] = [
     0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6,
@@ -21,56 +43,117 @@ const EMPTY_ROOT_HASH: [u8; 32] = [
     0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
 ];
 
+#[derive(Clone, Copy, Debug)]
 pub struct CommitResult {
-    root_hash: [u8; 32],
-    root_offset: i64,
+    pub root_hash: [u8; 32],
+    pub root_offset: u64,
 }
 
-pub struct Trie {
-    root_offset: Option<i64>,
-    store: Rc<RefCell<dyn Store>>,
+/// A trie over nodes kept in `S`. Using a concrete store lets node accesses
+/// be inlined; `DynTrie` is for callers that need to pick the store at runtime.
+pub struct Trie<S: Store + ?Sized = dyn Store> {
+    root: NodeRef,
+    store: Rc<RefCell<S>>,
     nodes: Vec<Node>,
+    // The offset of the root as last committed or opened.
+    base: Option<u64>,
+    // The root hash of `base`, once it's been needed.
+    base_hash: Option<[u8; 32]>,
+    flat: Option<FlatState>,
 }
 
-impl Trie {
-    pub fn new(store: Rc<RefCell<dyn Store>>, root_offset: Option<i64>) -> Self {
+/// A trie over a type-erased store.
+pub type DynTrie = Trie<dyn Store>;
+
+// Arena nodes written by a commit: their hash and the reference they were
+// written as.
+pub(crate) type Written = std::collections::HashMap<u32, (NodeHash, NodeRef)>;
+
+impl<S: Store + ?Sized> Trie<S> {
+    pub fn new(store: Rc<RefCell<S>>, root_offset: Option<u64>) -> Self {
         Self {
-            root_offset,
+            root: root_offset.map_or(NodeRef::Empty, NodeRef::Stored),
             store,
             nodes: Vec::with_capacity(65535),
+            base: root_offset,
+            base_hash: root_offset.is_none().then_some(EMPTY_ROOT_HASH),
+            flat: None,
         }
     }
 
-    pub fn new_empty(store: Rc<RefCell<dyn Store>>) -> Self {
+    pub fn new_empty(store: Rc<RefCell<S>>) -> Self {
         Trie::new(store, None)
     }
 
+    // A copy of the trie, uncommitted changes included, that can be changed
+    // on its own. The flat layer isn't carried over.
+    pub(crate) fn fork(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            store: Rc::clone(&self.store),
+            nodes: self.nodes.clone(),
+            base: self.base,
+            base_hash: self.base_hash,
+            flat: None,
+        }
+    }
+
+    /// Opens the trie with the given root hash, which the store must be able
+    /// to find with `Store::get_by_hash`.
+    pub fn from_root_hash(store: Rc<RefCell<S>>, root_hash: &[u8; 32]) -> Result<Self, Box<dyn std::error::Error>> {
+        if *root_hash == EMPTY_ROOT_HASH {
+            return Ok(Trie::new_empty(store));
+        }
+
+        let found = store.borrow_mut().get_by_hash(root_hash)?;
+        let (offset, _) = found.ok_or("root not found")?;
+        Ok(Trie::new(store, Some(offset)))
+    }
+
     pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let mut path = Nibbles::from_bytes(key);
-        if self.root_offset.is_none() {
-            let leaf = Node::Leaf(Leaf::new(path, value.to_vec()));
-            self.root_offset = Some(self.intern(leaf));
+        self.insert_value(key, value)?;
+        if let Some(flat) = &mut self.flat {
+            flat.record(key, value);
+        }
+        Ok(())
+    }
+
+    fn insert_value(&mut self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut path = NibbleSlice::new(key);
+        if self.root.is_empty() {
+            let leaf = Node::Leaf(Leaf::new(path.to_nibbles(), value.to_vec()));
+            self.root = NodeRef::Dirty(self.intern(leaf));
             return Ok(());
         }
 
-        // If the root offset is > 0, immediately intern it since it's dirty.
-        if self.root_offset.unwrap() > 0 {
-            let root = self.get_node(self.root_offset.unwrap())?;
-            let new_root = root.clone();
-            self.root_offset = Some(self.intern(new_root));
+        // If the root is clean, immediately intern it since it's about to change.
+        if !matches!(self.root, NodeRef::Dirty(_)) {
+            let root = self.get_node(&self.root)?;
+            self.root = NodeRef::Dirty(self.intern(root));
         }
 
-        let mut current_node_id = self.root_offset.unwrap();
+        let NodeRef::Dirty(mut current_node_id) = self.root else {
+            unreachable!("root was interned above");
+        };
         loop {
-            let mut current_node = self.get_node(current_node_id)?;
+            // A stored child the walk continues into is read before the node
+            // is taken out of the arena, so a failed read leaves it in place.
+            let mut child = match stored_child(&self.nodes[current_node_id as usize], &path) {
+                Some(child_ref) => Some(self.get_node(child_ref)?),
+                None => None,
+            };
+
+            // Every path below puts a node back at `current_node_id`.
+            let placeholder = Node::Leaf(Leaf::default());
+            let mut current_node = std::mem::replace(&mut self.nodes[current_node_id as usize], placeholder);
             current_node.set_dirty(true);
             current_node.set_committed(false);
 
             match current_node {
                 Node::Leaf(mut leaf) => {
-                    let shared_prefix = leaf.path.intersection(&path);
+                    let shared_prefix = leaf.path.as_slice().common_prefix_len(&path);
 
-                    if shared_prefix.len() == leaf.path.len() && shared_prefix.len() == path.len() {
+                    if shared_prefix == leaf.path.len() && shared_prefix == path.len() {
                         leaf.value = value.to_vec();
                         self.insert_node(current_node_id, Node::Leaf(leaf));
                         break;
@@ -78,27 +161,28 @@ impl Trie {
 
                     let mut branch = Branch::new();
 
-                    if shared_prefix.len() == path.len() {
+                    if shared_prefix == path.len() {
                         branch.value = Some(value.to_vec());
-                    } else if shared_prefix.len() == leaf.path.len() {
-                        branch.value = Some(leaf.value.clone());
+                    } else if shared_prefix == leaf.path.len() {
+                        branch.value = Some(std::mem::take(&mut leaf.value));
                     }
 
-                    if shared_prefix.len() < leaf.path.len() {
-                        let child_nibble = leaf.path.at(shared_prefix.len());
-                        let branch_path = leaf.path.slice_from(shared_prefix.len() + 1);
-                        branch.children[child_nibble] = self.intern(Node::Leaf(Leaf::new(branch_path, leaf.value.clone())));
+                    if shared_prefix < leaf.path.len() {
+                        let child_nibble = leaf.path.at(shared_prefix);
+                        let branch_path = leaf.path.as_slice().slice_from(shared_prefix + 1).to_nibbles();
+                        let leaf_value = std::mem::take(&mut leaf.value);
+                        branch.children[child_nibble] = NodeRef::Dirty(self.intern(Node::Leaf(Leaf::new(branch_path, leaf_value))));
                     }
 
-                    if shared_prefix.len() < path.len() {
-                        let child_path = path.at(shared_prefix.len());
-                        let branch_path = path.slice_from(shared_prefix.len() + 1);
-                        branch.children[child_path] = self.intern(Node::Leaf(Leaf::new(branch_path, value.to_vec())));
+                    if shared_prefix < path.len() {
+                        let child_path = path.at(shared_prefix);
+                        let branch_path = path.slice_from(shared_prefix + 1).to_nibbles();
+                        branch.children[child_path] = NodeRef::Dirty(self.intern(Node::Leaf(Leaf::new(branch_path, value.to_vec()))));
                     }
 
-                    if shared_prefix.len() > 0 {
-                        let branch_id = self.intern(Node::Branch(branch));
-                        let ext_path = leaf.path.slice_to(shared_prefix.len());
+                    if shared_prefix > 0 {
+                        let branch_id = NodeRef::Dirty(self.intern(Node::Branch(branch)));
+                        let ext_path = leaf.path.as_slice().slice_to(shared_prefix).to_nibbles();
 
                         self.insert_node(current_node_id, Node::Extension(Extension::new(ext_path, branch_id)));
                     } else {
@@ -108,54 +192,56 @@ impl Trie {
                     break;
                 }
                 Node::Extension(mut ext) => {
-                    let shared_prefix = ext.path.intersection(&path);
-
-                    let child = self.get_node(ext.child)?;
-
-                    // Shared prefix is the same, replace the child.
-                    if shared_prefix.len() == ext.path.len() {
-                        path = path.slice_from(shared_prefix.len());
-                        let new_child = child.clone();
-                        ext.child = self.intern(new_child);
-                        self.insert_node(current_node_id, Node::Extension(ext.clone()));
-                        current_node_id = ext.child;
+                    let shared_prefix = ext.path.as_slice().common_prefix_len(&path);
+
+                    // Shared prefix is the same, continue into the child, making
+                    // a dirty copy of it first if it isn't one already.
+                    if shared_prefix == ext.path.len() {
+                        path = path.slice_from(shared_prefix);
+                        let child_id = match ext.child {
+                            NodeRef::Dirty(child_id) => child_id,
+                            _ => self.intern(child.take().expect("stored child was read")),
+                        };
+                        ext.child = NodeRef::Dirty(child_id);
+                        self.insert_node(current_node_id, Node::Extension(ext));
+                        current_node_id = child_id;
                         continue;
                     }
 
                     // Shared prefix is a subset of the extension path, split the extension.
-                    let matched_path = ext.path.slice_to(shared_prefix.len());
-                    let branch_nibble = ext.path.at(shared_prefix.len());
-                    let unmatched_path = ext.path.slice_from(shared_prefix.len() + 1);
+                    let matched_path = ext.path.as_slice().slice_to(shared_prefix).to_nibbles();
+                    let branch_nibble = ext.path.at(shared_prefix);
+                    let unmatched_path = ext.path.as_slice().slice_from(shared_prefix + 1).to_nibbles();
 
                     let mut branch = Branch::new();
 
-                    if unmatched_path.len() == 0 {
+                    if unmatched_path.is_empty() {
                         branch.children[branch_nibble] = ext.child;
                     } else {
-                        branch.children[branch_nibble] = self.intern(Node::Extension(Extension::new(unmatched_path, ext.child)));
+                        branch.children[branch_nibble] = NodeRef::Dirty(self.intern(Node::Extension(Extension::new(unmatched_path, ext.child))));
                     }
 
-                    if shared_prefix.len() < path.len() {
-                        let child_path = path.at(shared_prefix.len());
-                        let branch_path = path.slice_from(shared_prefix.len() + 1);
-                        branch.children[child_path] = self.intern(Node::Leaf(Leaf::new(branch_path, value.to_vec())));
-                    } else if shared_prefix.len() == path.len() {
+                    if shared_prefix < path.len() {
+                        let child_path = path.at(shared_prefix);
+                        let branch_path = path.slice_from(shared_prefix + 1).to_nibbles();
+                        branch.children[child_path] = NodeRef::Dirty(self.intern(Node::Leaf(Leaf::new(branch_path, value.to_vec()))));
+                    } else if shared_prefix == path.len() {
                         branch.value = Some(value.to_vec());
                     } else {
-                        unreachable!("shared_prefix.len() > path.len() -> should never happen");
+                        unreachable!("shared_prefix > path.len() -> should never happen");
                     }
 
-                    if matched_path.len() == 0 {
+                    if matched_path.is_empty() {
                         self.insert_node(current_node_id, Node::Branch(branch));
                     } else {
-                        let branch_id = self.intern(Node::Branch(branch));
+                        let branch_id = NodeRef::Dirty(self.intern(Node::Branch(branch)));
                         self.insert_node(current_node_id, Node::Extension(Extension::new(matched_path, branch_id)));
                     }
 
                     break;
                 }
                 Node::Branch(mut branch) => {
-                    if path.len() == 0 {
+                    if path.is_empty() {
                         branch.value = Some(value.to_vec());
                         self.insert_node(current_node_id, Node::Branch(branch));
                         break;
@@ -165,27 +251,25 @@ impl Trie {
                     path = path.slice_from(1);
 
                     // This branch has no child at the branch nibble, so we create a leaf node.
-                    if branch.children[branch_nibble] == 0 {
-                        branch.children[branch_nibble] = self.intern(Node::Leaf(Leaf::new(path, value.to_vec())));
+                    if branch.children[branch_nibble].is_empty() {
+                        branch.children[branch_nibble] = NodeRef::Dirty(self.intern(Node::Leaf(Leaf::new(path.to_nibbles(), value.to_vec()))));
                         self.insert_node(current_node_id, Node::Branch(branch));
                         break;
                     }
 
-                    let child_offset = branch.children[branch_nibble];
-
                     // This node is already dirty, so we can just traverse into it.
-                    if child_offset < 0 {
-                        current_node_id = child_offset;
+                    if let NodeRef::Dirty(child_id) = branch.children[branch_nibble] {
+                        self.insert_node(current_node_id, Node::Branch(branch));
+                        current_node_id = child_id;
                         continue;
                     }
 
                     // This node is not dirty, so we need to create a cloned dirty node
-                    // at this offset.
-                    let child = self.get_node(child_offset)?;
-                    let new_child = child.clone();
-                    branch.children[branch_nibble] = self.intern(new_child);
-                    self.insert_node(current_node_id, Node::Branch(branch.clone()));
-                    current_node_id = branch.children[branch_nibble];
+                    // in the arena.
+                    let child_id = self.intern(child.take().expect("stored child was read"));
+                    branch.children[branch_nibble] = NodeRef::Dirty(child_id);
+                    self.insert_node(current_node_id, Node::Branch(branch));
+                    current_node_id = child_id;
                 }
             }
         }
@@ -193,192 +277,364 @@ impl Trie {
         Ok(())
     }
 
-    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        if self.root_offset.is_none() {
-            return Err("root not found".into());
+    /// Removes `key` from the trie, returning whether it was there. Branches
+    /// left with a single child or just a value are collapsed, so the trie
+    /// ends up the same as if the key had never been inserted.
+    pub fn remove(&mut self, key: &[u8]) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.root.is_empty() {
+            return Ok(false);
         }
 
-        let mut path = Nibbles::from_bytes(key);
-        let mut current_node_id = self.root_offset.unwrap();
-
-        loop {
-            let current_node = self.get_node(current_node_id)?;
+        let root = self.root.clone();
+        let Some(root) = self.remove_at(&root, NibbleSlice::new(key))? else {
+            return Ok(false);
+        };
+        self.root = root;
 
-            match current_node {
-                Node::Leaf(leaf) => {
-                    let shared_prefix = leaf.path.intersection(&path);
+        if let Some(flat) = &mut self.flat {
+            flat.record_removal(key);
+        }
+        Ok(true)
+    }
 
-                    if shared_prefix.len() == leaf.path.len() && shared_prefix.len() == path.len() {
-                        return Ok(leaf.value.clone());
+    // Removes `path` from the subtree at `node_ref`. Returns what replaces the
+    // subtree, or `None` if the path isn't in it.
+    fn remove_at(&mut self, node_ref: &NodeRef, path: NibbleSlice) -> Result<Option<NodeRef>, Box<dyn std::error::Error>> {
+        let node = self.get_node(node_ref)?;
+        let replacement = match node {
+            Node::Leaf(leaf) => {
+                let shared_prefix = leaf.path.as_slice().common_prefix_len(&path);
+                if shared_prefix != leaf.path.len() || shared_prefix != path.len() {
+                    return Ok(None);
+                }
+                return Ok(Some(NodeRef::Empty));
+            }
+            Node::Extension(ext) => {
+                if ext.path.as_slice().common_prefix_len(&path) != ext.path.len() {
+                    return Ok(None);
+                }
+                let Some(child) = self.remove_at(&ext.child, path.slice_from(ext.path.len()))? else {
+                    return Ok(None);
+                };
+                if child.is_empty() {
+                    return Ok(Some(NodeRef::Empty));
+                }
+                self.prefixed(ext.path, child)?
+            }
+            Node::Branch(mut branch) => {
+                if path.is_empty() {
+                    if branch.value.take().is_none() {
+                        return Ok(None);
                     }
-
-                    return Err("key not found".into());
+                } else {
+                    let nibble = path.at(0);
+                    if branch.children[nibble].is_empty() {
+                        return Ok(None);
+                    }
+                    let child = branch.children[nibble].clone();
+                    let Some(child) = self.remove_at(&child, path.slice_from(1))? else {
+                        return Ok(None);
+                    };
+                    branch.children[nibble] = child;
                 }
-                Node::Extension(ext) => {
-                    let shared_prefix = ext.path.intersection(&path);
 
-                    if shared_prefix.len() != ext.path.len() {
-                        return Err("key not found".into());
+                let mut children = branch.children.iter().enumerate().filter(|(_, child)| !child.is_empty());
+                match (children.next(), children.next(), &branch.value) {
+                    // Only the value is left.
+                    (None, _, Some(_)) => Node::Leaf(Leaf::new(Nibbles::default(), branch.value.take().unwrap())),
+                    // Only one child is left, which takes the branch's place.
+                    (Some((nibble, child)), None, None) => {
+                        let mut prefix = Nibbles::default();
+                        prefix.push(nibble as u8);
+                        self.prefixed(prefix, child.clone())?
                     }
-
-                    current_node_id = ext.child;
-                    path = path.slice_from(shared_prefix.len());
+                    _ => Node::Branch(branch),
                 }
-                Node::Branch(branch) => {
-                    if path.len() == 0 {
-                        return match &branch.value {
-                            Some(value) => Ok(value.clone()),
-                            None => Err("key not found".into()),
-                        };
-                    }
+            }
+        };
 
-                    let branch_nibble = path.at(0);
-                    let child_offset = branch.children[branch_nibble];
-                    if child_offset == 0 {
-                        return Err("key not found".into());
-                    }
+        let mut node = replacement;
+        node.set_dirty(true);
+        node.set_committed(false);
+        Ok(Some(match node_ref {
+            NodeRef::Dirty(id) => {
+                self.insert_node(*id, node);
+                NodeRef::Dirty(*id)
+            }
+            _ => NodeRef::Dirty(self.intern(node)),
+        }))
+    }
 
-                    current_node_id = child_offset;
-                    path = path.slice_from(1);
+    // The node for `child` moved down `prefix`: leaves and extensions take the
+    // prefix onto their own path, branches get an extension in front.
+    fn prefixed(&mut self, prefix: Nibbles, child: NodeRef) -> Result<Node, Box<dyn std::error::Error>> {
+        let mut path = prefix;
+        Ok(match self.get_node(&child)? {
+            Node::Leaf(leaf) => {
+                path.extend(&leaf.path);
+                Node::Leaf(Leaf::new(path, leaf.value))
+            }
+            Node::Extension(ext) => {
+                path.extend(&ext.path);
+                Node::Extension(Extension::new(path, ext.child))
+            }
+            Node::Branch(_) => Node::Extension(Extension::new(path, child)),
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.get_with(key, |value| value.to_vec())
+    }
+
+    /// Looks up `key` and hands its value to `f` without copying it. Stored
+    /// nodes are read in place when the store can hand out views of them.
+    pub fn get_with<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Result<R, Box<dyn std::error::Error>> {
+        let fresh = self.flat.as_ref().is_some_and(|flat| flat.is_fresh(self.base_hash));
+        if self.root.is_empty() && !fresh {
+            return Err("root not found".into());
+        }
+
+        self.find_with(key, f)?.ok_or("key not found".into())
+    }
+
+    // Like `get_with`, but a missing key, including in an empty trie, is
+    // `None` rather than an error.
+    pub(crate) fn find_with<R>(&self, key: &[u8], f: impl FnOnce(&[u8]) -> R) -> Result<Option<R>, Box<dyn std::error::Error>> {
+        if let Some(flat) = self.flat.as_ref().filter(|flat| flat.is_fresh(self.base_hash)) {
+            return Ok(flat.get(key).map(f));
+        }
+
+        if self.root.is_empty() {
+            return Ok(None);
+        }
+
+        let mut path = NibbleSlice::new(key);
+        let mut current = self.root.clone();
+
+        loop {
+            if let NodeRef::Stored(offset) = current {
+                let mut store = self.store.borrow_mut();
+                if let Some(mut view) = store.view(offset)? {
+                    // Inline children are part of the same record, so the
+                    // walk only leaves the view at a stored child.
+                    current = loop {
+                        match step_view(&view, &mut path)? {
+                            Step::Found(value) => return Ok(Some(f(value))),
+                            Step::Missing => return Ok(None),
+                            Step::Next(ChildView::Inline(child)) => view = child,
+                            Step::Next(ChildView::Stored(offset)) => break NodeRef::Stored(offset),
+                            Step::Next(ChildView::Empty) => return Ok(None),
+                        }
+                    };
+                    continue;
                 }
             }
+
+            let node = self.get_node(&current)?;
+            match step_node(&node, &mut path)? {
+                Step::Found(value) => return Ok(Some(f(value))),
+                Step::Missing => return Ok(None),
+                Step::Next(child) => current = child.clone(),
+            }
+        }
+    }
+
+    /// The offset of the root, if it is stored.
+    pub fn root_offset(&self) -> Option<u64> {
+        self.root.offset()
+    }
+
+    pub fn iter(&self) -> TrieIter<'_, S> {
+        TrieIter::new(self, self.root.clone())
+    }
+
+    /// Attaches a flat key/value layer to serve point reads from. A layer
+    /// that doesn't match the current root is only read from once
+    /// `rebuild_flat` has caught it up.
+    pub fn attach_flat(&mut self, flat: FlatState) -> Result<(), Box<dyn std::error::Error>> {
+        if matches!(self.root, NodeRef::Dirty(_)) {
+            return Err("trie has uncommitted changes".into());
         }
+
+        if let (None, Some(base)) = (self.base_hash, self.base) {
+            let hash = self.store.borrow_mut().get(base)?.hash().ok_or("root has no hash")?;
+            self.base_hash = Some(root_hash(&hash));
+        }
+        self.flat = Some(flat);
+        Ok(())
+    }
+
+    pub fn detach_flat(&mut self) -> Option<FlatState> {
+        self.flat.take()
+    }
+
+    pub fn flat(&self) -> Option<&FlatState> {
+        self.flat.as_ref()
+    }
+
+    /// Rebuilds the flat layer from the committed trie, reading at most
+    /// `limit` entries per call so the work can be spread out between other
+    /// operations. Returns true once the layer is fresh.
+    pub fn rebuild_flat(&mut self, limit: usize) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut flat = self.flat.take().ok_or("no flat layer attached")?;
+        let result = flat.rebuild(self, limit);
+        self.flat = Some(flat);
+        result
+    }
+
+    /// Checks the flat layer against the root hash of the trie.
+    pub fn verify_flat(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        match &self.flat {
+            Some(flat) if flat.is_fresh(self.base_hash) => {}
+            Some(_) => return Err("flat layer is stale".into()),
+            None => return Err("no flat layer attached".into()),
+        }
+
+        let root_hash = self.calculate_root()?;
+        self.flat.as_ref().unwrap().verify(root_hash)
     }
 
     pub fn commit(&mut self) -> Result<CommitResult, Box<dyn std::error::Error>> {
-        if self.root_offset.is_none() {
+        self.commit_written().map(|(result, _)| result)
+    }
+
+    // Commits the trie, and also returns where each arena node that was
+    // written ended up, along with its hash, so that forks holding copies of
+    // them can be rebased.
+    pub(crate) fn commit_written(&mut self) -> Result<(CommitResult, Written), Box<dyn std::error::Error>> {
+        if self.root.is_empty() {
             return Err("root not found".into());
         }
 
         let root_hash = self.calculate_root()?;
-        let root_offset = self.write_node(&mut self.get_node(self.root_offset.unwrap())?)?;
-        self.root_offset = Some(root_offset);
+        let mut ids = Vec::new();
+        let root_offset = self.write_node(&mut self.get_node(&self.root)?, &mut ids)?;
+        let mut written = Written::new();
+        for (id, node_ref) in ids {
+            if let Some(hash) = self.nodes[id as usize].hash() {
+                written.insert(id, (hash, node_ref));
+            }
+        }
+        if let NodeRef::Dirty(id) = self.root {
+            if let Some(hash) = self.nodes[id as usize].hash() {
+                written.insert(id, (hash, NodeRef::Stored(root_offset)));
+            }
+        }
+        self.root = NodeRef::Stored(root_offset);
         self.nodes.clear();
         self.store.borrow_mut().flush()?;
+        self.store.borrow_mut().commit_root(root_offset)?;
+        if let Some(flat) = &mut self.flat {
+            flat.commit(self.base_hash, root_hash);
+        }
+        self.base = Some(root_offset);
+        self.base_hash = Some(root_hash);
 
-        Ok(CommitResult {
+        Ok((CommitResult {
             root_hash,
             root_offset,
-        })
+        }, written))
+    }
+
+    // Points this fork's references to arena nodes that another fork has
+    // since written at what they were written as, so that they aren't written
+    // again. A node only counts as the same if its hash still matches.
+    pub(crate) fn rebase(&mut self, written: &Written) {
+        let moved: Vec<Option<NodeRef>> = self.nodes.iter().enumerate()
+            .map(|(id, node)| {
+                let (hash, node_ref) = written.get(&(id as u32))?;
+                (!node.is_dirty() && node.hash() == Some(*hash)).then(|| node_ref.clone())
+            })
+            .collect();
+        let rebased = |node_ref: &mut NodeRef| {
+            if let NodeRef::Dirty(id) = node_ref {
+                if let Some(moved) = &moved[*id as usize] {
+                    *node_ref = moved.clone();
+                }
+            }
+        };
+
+        for node in &mut self.nodes {
+            match node {
+                Node::Branch(branch) => branch.children.iter_mut().for_each(rebased),
+                Node::Extension(ext) => rebased(&mut ext.child),
+                Node::Leaf(_) => {}
+            }
+        }
+        rebased(&mut self.root);
     }
 
-    fn intern(&mut self, node: Node) -> i64 {
-        let id = -100 - self.nodes.len() as i64;
+    fn intern(&mut self, node: Node) -> u32 {
         self.nodes.push(node);
-        id
+        (self.nodes.len() - 1) as u32
     }
 
-    fn get_node(&self, offset: i64) -> Result<Node, Box<dyn std::error::Error>> {
-        self.get_node_with_local_map(offset, &self.nodes)
+    fn get_node(&self, node_ref: &NodeRef) -> Result<Node, Box<dyn std::error::Error>> {
+        self.get_node_with_local_map(node_ref, &self.nodes)
     }
 
-    fn get_node_with_local_map(&self, offset: i64, nodes: &Vec<Node>) -> Result<Node, Box<dyn std::error::Error>> {
-        if offset < 0 {
-            return nodes.get(map_offset(offset))
+    fn get_node_with_local_map(&self, node_ref: &NodeRef, nodes: &[Node]) -> Result<Node, Box<dyn std::error::Error>> {
+        match node_ref {
+            NodeRef::Empty => Err("node not found".into()),
+            NodeRef::Dirty(id) => nodes.get(*id as usize)
                 .cloned()
-                .ok_or("node not found here".into());
+                .ok_or("node not found here".into()),
+            NodeRef::Stored(offset) => self.store.borrow_mut().get(*offset),
+            NodeRef::Inline(node) => Ok((**node).clone()),
         }
-
-        let node = self.store.borrow_mut().get(offset)?;
-        Ok(node)
     }
 
-    fn calculate_root(&mut self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
-        if self.root_offset.is_none() {
+    /// The root hash of the trie, including any uncommitted changes.
+    pub fn calculate_root(&mut self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        if self.root.is_empty() {
             return Ok(EMPTY_ROOT_HASH);
         }
 
         let mut nodes = std::mem::take(&mut self.nodes);
-        let hash = self.hash_node(self.root_offset.unwrap(), &mut nodes)?;
+        let hash = self.hash_node(&self.root, &mut nodes)?;
         self.nodes = nodes;
 
-        if hash.len() < 32 {
-            let mut hasher = tiny_keccak::Keccak::v256();
-            hasher.update(hash.as_slice());
-            let mut root_hash = [0u8; 32];
-            hasher.finalize(&mut root_hash);
-            return Ok(root_hash);
-        }
-
-        let mut root_hash = [0u8; 32];
-        root_hash.copy_from_slice(&hash[..32]);
-        Ok(root_hash)
+        Ok(root_hash(&hash))
     }
 
-    fn hash_node(&self, offset: i64, nodes: &mut Vec<Node>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let mut node = self.get_node_with_local_map(offset, nodes)?;
+    fn hash_node(&self, node_ref: &NodeRef, nodes: &mut Vec<Node>) -> Result<NodeHash, Box<dyn std::error::Error>> {
+        let mut node = self.get_node_with_local_map(node_ref, nodes)?;
 
         if !node.is_dirty() {
             return Ok(node.hash().expect("node is clean but has no hash"));
         }
 
-        let data = match node {
-            Node::Extension(ref ext) => {
-                let child_hash = self.hash_node(ext.child, nodes)?;
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&ext.path.prefixed_bytes(false));
-                if child_hash.len() < 32 {
-                    stream.append_raw(&child_hash, 1);
-                } else {
-                    stream.append_raw(rlp_hash(child_hash).as_slice(), 1);
-                }
-
-                stream.out().to_vec()
-            }
-            Node::Leaf(ref leaf) => {
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&leaf.path.prefixed_bytes(true))
-                    .append(&leaf.value);
-                stream.out().to_vec()
-            }
-            Node::Branch(ref branch) => {
-                let mut stream = RlpStream::new_list(17);
-                for child in &branch.children {
-                    if *child == 0 {
-                        stream.append_empty_data();
-                    } else {
-                        let child_hash = self.hash_node(*child, nodes)?;
-
-                        if child_hash.len() < 32 {
-                            stream.append_raw(&child_hash, 1);
-                        } else {
-                            stream.append_raw(rlp_hash(child_hash).as_slice(), 1);
-                        }
-                    }
-                }
-
-                match &branch.value {
-                    Some(value) => {
-                        stream.append(value)
-                    }
-                    None => {
-                        stream.append_empty_data()
-                    }
-                };
+        let out = compute_hash(&node, |_, child| self.hash_node(child, nodes))?;
 
-                stream.out().to_vec()
-            }
-        };
+        // Only arena nodes can be dirty.
+        if let NodeRef::Dirty(id) = node_ref {
+            node.set_hash(out);
+            node.set_dirty(false);
+            nodes[*id as usize] = node;
+        }
+        Ok(out)
+    }
 
-        let out = if data.len() < 32 {
-            data
-        } else {
-            let mut hash = [0u8; 32];
-            if data.len() >= 32 {
-                let mut hasher = tiny_keccak::Keccak::v256();
-                hasher.update(data.as_slice());
-                hasher.finalize(&mut hash);
+    // Writes the dirty subtree under `node` as a single batch and returns the
+    // offset `node` ended up at. The arena nodes below it are added to `ids`
+    // with what they were written as.
+    fn write_node(&mut self, node: &mut Node, ids: &mut Vec<(u32, NodeRef)>) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut batch = Vec::new();
+        self.collect_batch(node, &mut batch, ids, true)?;
+        let offsets = self.store.borrow_mut().put_batch(batch)?;
+        for (_, node_ref) in ids.iter_mut() {
+            if let NodeRef::Dirty(index) = node_ref {
+                *node_ref = NodeRef::Stored(offsets[*index as usize]);
             }
-            hash.to_vec()
-        };
-
-        node.set_hash(out.clone());
-        node.set_dirty(false);
-        nodes[map_offset(offset)] = node.clone();
-        Ok(out)
+        }
+        offsets.last().copied().ok_or("empty batch".into())
     }
 
-    fn write_node(&mut self, node: &mut Node) -> Result<i64, Box<dyn std::error::Error>> {
+    // Appends the subtree under `node` to `batch` in post-order, pointing each
+    // parent at its children by their position in the batch. Unless `node` is
+    // the root, it is embedded in its parent instead if its encoding is short
+    // enough to stand in for its hash.
+    fn collect_batch(&self, node: &mut Node, batch: &mut Vec<Node>, ids: &mut Vec<(u32, NodeRef)>, is_root: bool) -> Result<NodeRef, Box<dyn std::error::Error>> {
         if node.is_dirty() {
             return Err("node is dirty".into());
         }
@@ -388,21 +644,12 @@ impl Trie {
         }
 
         match node {
-            Node::Extension(ext) => {
-                if ext.child < 0 {
-                    let child = self.write_node(&mut self.get_node(ext.child)?)?;
-                    ext.child = child;
-                }
+            Node::Extension(ext) if matches!(ext.child, NodeRef::Dirty(_)) => {
+                ext.child = self.collect_child(&ext.child, batch, ids)?;
             }
             Node::Branch(branch) => {
-                let children = branch.children.clone();
-                for (i, child) in children.iter().enumerate() {
-                    if *child >= 0 {
-                        continue;
-                    }
-
-                    let child_offset = self.write_node(&mut self.get_node(*child)?)?;
-                    branch.children[i] = child_offset;
+                for child in branch.children.iter_mut().filter(|c| matches!(c, NodeRef::Dirty(_))) {
+                    *child = self.collect_child(child, batch, ids)?;
                 }
             }
             // Do nothing for leaves, since they are written directly.
@@ -410,12 +657,186 @@ impl Trie {
         }
 
         node.set_committed(true);
-        let offset = self.store.borrow_mut().put(node.clone())?;
-        Ok(offset)
+        if !is_root && node.hash().is_some_and(|hash| hash.is_embedded()) {
+            return Ok(NodeRef::Inline(Box::new(node.clone())));
+        }
+
+        batch.push(node.clone());
+        Ok(NodeRef::Dirty((batch.len() - 1) as u32))
+    }
+
+    fn collect_child(&self, child: &NodeRef, batch: &mut Vec<Node>, ids: &mut Vec<(u32, NodeRef)>) -> Result<NodeRef, Box<dyn std::error::Error>> {
+        let written = self.collect_batch(&mut self.get_node(child)?, batch, ids, false)?;
+        if let NodeRef::Dirty(id) = child {
+            ids.push((*id, written.clone()));
+        }
+        Ok(written)
+    }
+
+    fn insert_node(&mut self, id: u32, node: Node) {
+        self.nodes[id as usize] = node;
+    }
+}
+
+// Where a lookup goes from a node: either the value was found there or the
+// walk continues at a child.
+enum Step<'a, C> {
+    Found(&'a [u8]),
+    Missing,
+    Next(C),
+}
+
+// The stored child `Trie::insert` would continue into from `node`, if any.
+fn stored_child<'a>(node: &'a Node, path: &NibbleSlice) -> Option<&'a NodeRef> {
+    let child = match node {
+        Node::Extension(ext) if ext.path.as_slice().common_prefix_len(path) == ext.path.len() => &ext.child,
+        Node::Branch(branch) if !path.is_empty() => &branch.children[path.at(0)],
+        _ => return None,
+    };
+    (!child.is_empty() && !matches!(child, NodeRef::Dirty(_))).then_some(child)
+}
+
+fn step_node<'a>(node: &'a Node, path: &mut NibbleSlice) -> Result<Step<'a, &'a NodeRef>, Box<dyn std::error::Error>> {
+    match node {
+        Node::Leaf(leaf) => {
+            let shared_prefix = leaf.path.as_slice().common_prefix_len(path);
+
+            if shared_prefix == leaf.path.len() && shared_prefix == path.len() {
+                return Ok(Step::Found(&leaf.value));
+            }
+
+            Ok(Step::Missing)
+        }
+        Node::Extension(ext) => {
+            let shared_prefix = ext.path.as_slice().common_prefix_len(path);
+
+            if shared_prefix != ext.path.len() {
+                return Ok(Step::Missing);
+            }
+
+            *path = path.slice_from(shared_prefix);
+            Ok(Step::Next(&ext.child))
+        }
+        Node::Branch(branch) => {
+            if path.is_empty() {
+                return Ok(branch.value.as_deref().map_or(Step::Missing, Step::Found));
+            }
+
+            let branch_nibble = path.at(0);
+            if branch.children[branch_nibble].is_empty() {
+                return Ok(Step::Missing);
+            }
+
+            *path = path.slice_from(1);
+            Ok(Step::Next(&branch.children[branch_nibble]))
+        }
+    }
+}
+
+fn step_view<'a>(view: &NodeView<'a>, path: &mut NibbleSlice) -> Result<Step<'a, ChildView<'a>>, Box<dyn std::error::Error>> {
+    if view.is_branch() {
+        if path.is_empty() {
+            return Ok(view.value().map_or(Step::Missing, Step::Found));
+        }
+
+        let branch_nibble = path.at(0);
+        *path = path.slice_from(1);
+        return Ok(Step::Next(view.child(branch_nibble)?));
+    }
+
+    let node_path = view.path().ok_or("node has no path")?;
+    let shared_prefix = node_path.common_prefix_len(path);
+    if view.is_leaf() {
+        if shared_prefix == node_path.len() && shared_prefix == path.len() {
+            return Ok(view.value().map_or(Step::Missing, Step::Found));
+        }
+
+        return Ok(Step::Missing);
+    }
+
+    if shared_prefix != node_path.len() {
+        return Ok(Step::Missing);
+    }
+
+    *path = path.slice_from(shared_prefix);
+    Ok(Step::Next(view.child(0)?))
+}
+
+// Hashes `node` by the Ethereum rules, given a way to get the hashes of its
+// children from their index in the node. An extension's child is at 0.
+pub(crate) fn compute_hash(node: &Node, mut child_hash: impl FnMut(usize, &NodeRef) -> Result<NodeHash, Box<dyn std::error::Error>>) -> Result<NodeHash, Box<dyn std::error::Error>> {
+    let data = match node {
+        Node::Extension(ext) => {
+            let child_hash = child_hash(0, &ext.child)?;
+            let mut stream = RlpStream::new_list(2);
+            stream.append(&ext.path.prefixed_bytes(false));
+            append_child(&mut stream, &child_hash);
+
+            stream.out().to_vec()
+        }
+        Node::Leaf(leaf) => {
+            let mut stream = RlpStream::new_list(2);
+            stream.append(&leaf.path.prefixed_bytes(true))
+                .append(&leaf.value);
+            stream.out().to_vec()
+        }
+        Node::Branch(branch) => {
+            let mut stream = RlpStream::new_list(17);
+            for (i, child) in branch.children.iter().enumerate() {
+                if child.is_empty() {
+                    stream.append_empty_data();
+                } else {
+                    let child_hash = child_hash(i, child)?;
+                    append_child(&mut stream, &child_hash);
+                }
+            }
+
+            match &branch.value {
+                Some(value) => {
+                    stream.append(value)
+                }
+                None => {
+                    stream.append_empty_data()
+                }
+            };
+
+            stream.out().to_vec()
+        }
+    };
+
+    Ok(if data.len() < 32 {
+        NodeHash::new(&data)
+    } else {
+        let mut hash = [0u8; 32];
+        let mut hasher = tiny_keccak::Keccak::v256();
+        hasher.update(data.as_slice());
+        hasher.finalize(&mut hash);
+        NodeHash::from(hash)
+    })
+}
+
+// Root nodes are always hashed, even when their encoding is short enough to be
+// embedded in a parent.
+pub(crate) fn root_hash(node_hash: &[u8]) -> [u8; 32] {
+    let mut root_hash = [0u8; 32];
+    if node_hash.len() < 32 {
+        let mut hasher = tiny_keccak::Keccak::v256();
+        hasher.update(node_hash);
+        hasher.finalize(&mut root_hash);
+    } else {
+        root_hash.copy_from_slice(&node_hash[..32]);
     }
+    root_hash
+}
 
-    fn insert_node(&mut self, offset: i64, node: Node) {
-        self.nodes[map_offset(offset)] = node;
+// Embedded children go into the parent as is, hashed ones as a 32-byte string.
+fn append_child(stream: &mut RlpStream, child_hash: &NodeHash) {
+    if child_hash.is_embedded() {
+        stream.append_raw(child_hash, 1);
+    } else {
+        let mut item = [0x80 + 32u8; 33];
+        item[1..].copy_from_slice(child_hash);
+        stream.append_raw(&item, 1);
     }
 }
 
@@ -458,6 +879,55 @@ mod tests {
         Ok(())
     }
 
+    // Fails every read once `reads_left` runs out.
+    #[derive(Default)]
+    struct FailingStore {
+        store: MemoryStore,
+        reads_left: Option<usize>,
+    }
+
+    impl Store for FailingStore {
+        fn get(&mut self, offset: u64) -> Result<Node, Box<dyn std::error::Error>> {
+            match &mut self.reads_left {
+                Some(0) => return Err("read failed".into()),
+                Some(left) => *left -= 1,
+                None => {}
+            }
+            self.store.get(offset)
+        }
+
+        fn put(&mut self, node: Node) -> Result<u64, Box<dyn std::error::Error>> {
+            self.store.put(node)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.store.flush()
+        }
+    }
+
+    #[test]
+    fn test_failed_insert_keeps_trie() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Rc::new(RefCell::new(FailingStore::default()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        let result = trie.commit()?;
+
+        // The root is read, but the stored child under it isn't.
+        store.borrow_mut().reads_left = Some(1);
+        assert!(trie.insert(b"dog", b"puppy").is_err());
+        store.borrow_mut().reads_left = None;
+
+        assert_eq!(trie.calculate_root()?, result.root_hash);
+        trie.insert(b"dog", b"puppy")?;
+        assert_eq!(
+            hex::encode(trie.calculate_root()?),
+            "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84",
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
         let ms = MemoryStore::new();
@@ -478,6 +948,92 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_small_nodes_are_inlined() -> Result<(), Box<dyn std::error::Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        trie.insert(b"a", b"1")?;
+        trie.insert(b"b", b"2")?;
+        trie.insert(b"c", &[3; 40])?;
+        let result = trie.commit()?;
+
+        // The keys share their first nibble, so the root is an extension over
+        // a branch. The two short leaves are embedded in the branch, leaving
+        // the long leaf, the branch and the root as the only records.
+        assert_eq!(result.root_offset, 2);
+        assert!(store.borrow_mut().get(3).is_err());
+        match store.borrow_mut().get(1)? {
+            Node::Branch(branch) => {
+                assert!(matches!(branch.children[1], NodeRef::Inline(_)));
+                assert!(matches!(branch.children[2], NodeRef::Inline(_)));
+                assert_eq!(branch.children[3].offset(), Some(0));
+            }
+            _ => panic!("expected a branch"),
+        }
+
+        let trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+        assert_eq!(trie.get(b"a")?, b"1");
+        assert_eq!(trie.get(b"b")?, b"2");
+        assert_eq!(trie.get(b"c")?, vec![3; 40]);
+        assert_eq!(trie.iter().count(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove() -> Result<(), Box<dyn std::error::Error>> {
+        // Short keys that are often prefixes of each other, with values both
+        // short enough to be inlined and not.
+        let mut seed = hmac_sha256::Hash::hash(b"remove");
+        let mut entries = std::collections::BTreeMap::new();
+        for _ in 0..300 {
+            seed = hmac_sha256::Hash::hash(&seed);
+            let key = seed[..1 + seed[31] as usize % 3].to_vec();
+            entries.insert(key, vec![seed[30]; 1 + seed[29] as usize % 40]);
+        }
+
+        let root_of = |entries: &std::collections::BTreeMap<Vec<u8>, Vec<u8>>| -> Result<[u8; 32], Box<dyn std::error::Error>> {
+            let mut trie = Trie::new_empty(Rc::new(RefCell::new(MemoryStore::new())));
+            for (key, value) in entries {
+                trie.insert(key, value)?;
+            }
+            trie.calculate_root()
+        };
+
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for (key, value) in &entries {
+            trie.insert(key, value)?;
+        }
+        trie.commit()?;
+
+        // Removing from stored nodes, then from the arena, matches a trie
+        // that never had the keys.
+        let keys: Vec<_> = entries.keys().cloned().collect();
+        for (i, key) in keys.iter().enumerate() {
+            if i % 3 == 0 {
+                assert!(trie.remove(key)?);
+                assert!(!trie.remove(key)?);
+                entries.remove(key);
+            }
+            if i % 50 == 49 {
+                assert_eq!(trie.calculate_root()?, root_of(&entries)?);
+                let result = trie.commit()?;
+                assert_eq!(result.root_hash, root_of(&entries)?);
+                let report = crate::check(&mut *store.borrow_mut(), result.root_offset);
+                assert!(report.is_ok(), "{:?}", report.problems);
+            }
+        }
+        assert_eq!(trie.calculate_root()?, root_of(&entries)?);
+        assert!(trie.get(&keys[0]).is_err());
+        assert_eq!(trie.get(&keys[1])?, entries[&keys[1]]);
+
+        for key in &keys {
+            trie.remove(key)?;
+        }
+        assert_eq!(trie.calculate_root()?, EMPTY_ROOT_HASH);
+        Ok(())
+    }
+
     #[cfg(feature = "bench")]
     mod bench {
         use crate::store::{CachingStore, FileStore};
@@ -488,17 +1044,16 @@ mod tests {
         fn bench_10000_sets() -> Result<(), Box<dyn std::error::Error>> {
             let file_store = FileStore::new("/tmp/test.db")?;
             let cache_store = CachingStore::new(file_store);
-            let store: Rc<RefCell<dyn Store>> = Rc::new(RefCell::new(cache_store));
+            let store = Rc::new(RefCell::new(cache_store));
             let mut trie = Trie::new_empty(Rc::clone(&store));
 
             let binding = hex::decode("f8448080a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470")?;
             let empty_acc = binding.as_slice();
 
             let mut seed = hmac_sha256::Hash::hash(b"all your base are belong to us");
-            ;
             let mut last_result: CommitResult;
             for _ in 0..25 {
-                let mut inputs = get_kvs(&seed);
+                let inputs = get_kvs(&seed);
                 seed = inputs.1;
 
                 println!("starting 10000 sets");
@@ -514,33 +1069,17 @@ mod tests {
 
             Ok(())
         }
-    }
-}
-
-fn get_kvs(data: &[u8; 32]) -> ([[u8; 32]; 10000], [u8; 32]) {
-    let mut last_data = data;
-    let mut out = [[0; 32]; 10000];
 
-    for i in 0..10000 {
-        out[i] = hmac_sha256::Hash::hash(last_data);
-        last_data = &out[i];
-    }
+        fn get_kvs(data: &[u8; 32]) -> ([[u8; 32]; 10000], [u8; 32]) {
+            let mut last_data = *data;
+            let mut out = [[0; 32]; 10000];
 
-    (out, *last_data)
-}
+            for item in out.iter_mut() {
+                *item = hmac_sha256::Hash::hash(&last_data);
+                last_data = *item;
+            }
 
-fn rlp_hash(hash: Vec<u8>) -> Vec<u8> {
-    if hash.len() != 32 {
-        panic!("hash must be 32 bytes");
+            (out, last_data)
+        }
     }
-
-    [[0x80 + 32u8].as_slice(), hash.as_slice()].concat()
 }
-
-fn map_offset(offset: i64) -> usize {
-    if offset > -100 {
-        panic!("offset must be negative");
-    }
-
-    ((offset + 100) * -1) as usize
-}
\ No newline at end of file
@@ -0,0 +1,185 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process::ExitCode;
+use std::rc::Rc;
+
+use fftrie::snapshot::{self, Format};
+use fftrie::store::FileStore;
+use fftrie::Trie;
+
+const USAGE: &str = "\
+usage: fftrie <command> <store> [options]
+
+commands:
+    root <store> [offset]        print the root hash at an offset, the last root by default
+    get <store> <key>            print the value of a hex key
+    dump <store>                 stream key/values as JSON lines
+        --nodes                  stream the stored nodes instead
+        --cbor                   write key/values as CBOR
+    stats <store>                print a summary of the store and the shape of the trie
+    check <store>                check the trie for problems, exiting with 1 if any are found
+    compact <store>              rewrite the store with only the latest roots
+        --keep <n>               how many roots to keep, 1 by default
+
+root, get, dump, stats and check take --root <offset> to use another root than the last one.
+";
+
+// Options that are followed by a value.
+const VALUE_OPTIONS: &[&str] = &["--root", "--keep"];
+
+struct Args<'a> {
+    positional: Vec<&'a str>,
+    options: HashMap<&'a str, Option<&'a str>>,
+}
+
+impl<'a> Args<'a> {
+    fn parse(args: &'a [String]) -> Result<Self, Box<dyn Error>> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                positional.push(arg.as_str());
+            } else if VALUE_OPTIONS.contains(&arg.as_str()) {
+                let value = args.next().ok_or(format!("{} needs a value", arg))?;
+                options.insert(arg.as_str(), Some(value.as_str()));
+            } else {
+                options.insert(arg.as_str(), None);
+            }
+        }
+        Ok(Self { positional, options })
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    fn number(&self, name: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        match self.options.get(name).copied().flatten() {
+            Some(value) => Ok(Some(value.parse().map_err(|_| format!("invalid value for {}: {}", name, value))?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        // Output cut short by e.g. `head` isn't an error.
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("fftrie: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<ExitCode, Box<dyn Error>> {
+    let args = Args::parse(args)?;
+    let (command, path) = match args.positional[..] {
+        [command, path, ..] => (command, path),
+        _ => {
+            eprint!("{}", USAGE);
+            return Ok(ExitCode::from(2));
+        }
+    };
+
+    if !Path::new(path).exists() {
+        return Err(format!("{}: no such store", path).into());
+    }
+    // Checked before opening, which rolls the store back for compacting.
+    let keep = args.number("--keep")?.unwrap_or(1) as usize;
+    if keep == 0 {
+        return Err("--keep must be at least 1".into());
+    }
+
+    // Only compacting writes to the store. Everything else leaves it exactly
+    // as it is, even past its last durable commit.
+    let store = match command {
+        "compact" => FileStore::open(path)?,
+        _ => FileStore::open_read_only(path)?,
+    };
+    let store = Rc::new(RefCell::new(store));
+    let mut out = io::stdout().lock();
+
+    match command {
+        "root" => {
+            let offset = match args.positional.get(2) {
+                Some(offset) => offset.parse().map_err(|_| format!("invalid offset: {}", offset))?,
+                None => root(&store, &args)?,
+            };
+            let mut trie = Trie::new(Rc::clone(&store), Some(offset));
+            writeln!(out, "0x{}", hex::encode(trie.calculate_root()?))?;
+        }
+        "get" => {
+            let key = args.positional.get(2).ok_or("get needs a key")?;
+            let key = hex::decode(key.strip_prefix("0x").unwrap_or(key))?;
+            let trie = Trie::new(Rc::clone(&store), Some(root(&store, &args)?));
+            writeln!(out, "0x{}", hex::encode(trie.get(&key)?))?;
+        }
+        "dump" => {
+            if args.flag("--nodes") {
+                let offset = root(&store, &args)?;
+                snapshot::dump_nodes(&mut *store.borrow_mut(), offset, &mut out)?;
+            } else {
+                let format = if args.flag("--cbor") { Format::Cbor } else { Format::JsonLines };
+                let offset = match store.borrow().last_root() {
+                    Some(_) => Some(root(&store, &args)?),
+                    None => None,
+                };
+                snapshot::export(&mut Trie::new(Rc::clone(&store), offset), &mut out, format)?;
+            }
+        }
+        "stats" => {
+            let (roots, segments, durable) = {
+                let store = store.borrow();
+                (store.roots().len(), store.segment_count(), store.durable_root())
+            };
+            writeln!(out, "segments: {}", segments)?;
+            writeln!(out, "roots: {}", roots)?;
+            if let Some((offset, hash)) = durable {
+                writeln!(out, "durable root: {} 0x{}", offset, hex::encode(hash))?;
+            }
+            if roots > 0 {
+                let offset = root(&store, &args)?;
+                let mut trie = Trie::new(Rc::clone(&store), Some(offset));
+                writeln!(out, "root: {}", offset)?;
+                write!(out, "{}", trie.stats()?)?;
+            }
+        }
+        "check" => {
+            let offset = root(&store, &args)?;
+            let report = fftrie::check(&mut *store.borrow_mut(), offset);
+            for problem in &report.problems {
+                writeln!(out, "{}", problem)?;
+            }
+            writeln!(out, "{} nodes checked, {} problems", report.nodes, report.problems.len())?;
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        "compact" => {
+            let mut store = store.borrow_mut();
+            let roots = store.roots();
+            let roots = roots[roots.len().saturating_sub(keep)..].to_vec();
+            let new_roots = store.compact(&roots)?;
+            writeln!(out, "kept {} roots, last root now at {}", new_roots.len(), new_roots.last().map_or("none".to_string(), u64::to_string))?;
+        }
+        _ => return Err(format!("unknown command: {}", command).into()),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+// The root picked with --root, or the last committed one.
+fn root(store: &Rc<RefCell<FileStore>>, args: &Args) -> Result<u64, Box<dyn Error>> {
+    match args.number("--root")? {
+        Some(offset) => Ok(offset),
+        None => store.borrow().last_root().ok_or("store has no roots".into()),
+    }
+}
@@ -1,112 +1,235 @@
 use serde::{Deserialize, Serialize};
 
+/// An owned nibble path, packed two nibbles to a byte, high nibble first. When
+/// the length is odd, the low half of the last byte is always zero.
 #[derive(Clone, PartialEq, Debug, Default)]
 pub struct Nibbles {
     data: Vec<u8>,
+    len: usize,
+}
+
+/// A borrowed view of a range of nibbles. Slicing a view never allocates.
+#[derive(Clone, Copy, Debug)]
+pub struct NibbleSlice<'a> {
+    data: &'a [u8],
+    start: usize,
+    end: usize,
 }
 
 impl Nibbles {
-    pub fn from_raw_bytes(bytes: &[u8]) -> Self {
+    /// Takes `len` nibbles from bytes already in the packed layout.
+    pub fn from_packed(bytes: &[u8], len: usize) -> Self {
+        let mut data = bytes[..len.div_ceil(2)].to_vec();
+        if len % 2 == 1 {
+            *data.last_mut().unwrap() &= 0xF0;
+        }
+
         Self {
-            data: bytes.to_vec(),
+            data,
+            len,
         }
     }
 
     pub fn from_bytes(bytes: &[u8]) -> Self {
-        let mut data = Vec::with_capacity(bytes.len() * 2);
-        for byte in bytes {
-            data.push(byte >> 4);
-            data.push(byte & 0x0F);
+        Self {
+            data: bytes.to_vec(),
+            len: bytes.len() * 2,
         }
+    }
 
-        Self {
-            data,
+    pub fn as_slice(&self) -> NibbleSlice<'_> {
+        NibbleSlice {
+            data: &self.data,
+            start: 0,
+            end: self.len,
         }
     }
 
-    pub fn intersection(&self, other: &Self) -> Self {
-        let mut result = Vec::new();
-        let mut other_iter = other.data.iter();
-        for nibble in &self.data {
-            match other_iter.next() {
-                None => break,
-                Some(other_nibble) => {
-                    if nibble == other_nibble {
-                        result.push(*nibble);
-                    } else {
-                        break;
-                    }
-                }
-            }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn at(&self, index: usize) -> usize {
+        self.as_slice().at(index)
+    }
+
+    /// The packed bytes, as understood by `from_packed`.
+    pub fn packed(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The hex-prefix encoding used when hashing leaves and extensions.
+    pub fn prefixed_bytes(&self, leaf: bool) -> Vec<u8> {
+        let flag = if leaf { 0x20 } else { 0x00 };
+        if self.len.is_multiple_of(2) {
+            let mut result = Vec::with_capacity(1 + self.data.len());
+            result.push(flag);
+            result.extend_from_slice(&self.data);
+            return result;
         }
 
-        Self {
-            data: result,
+        // An odd path puts its first nibble next to the flag, which shifts
+        // every other nibble over by one.
+        let mut result = Vec::with_capacity(self.data.len());
+        result.push(flag | 0x10 | self.at(0) as u8);
+        for i in (1..self.len).step_by(2) {
+            result.push(((self.at(i) << 4) | self.at(i + 1)) as u8);
         }
+        result
     }
 
-    pub fn len(&self) -> usize {
-        self.data.len()
+    pub fn push(&mut self, nibble: u8) {
+        if self.len.is_multiple_of(2) {
+            self.data.push(nibble << 4);
+        } else {
+            *self.data.last_mut().unwrap() |= nibble & 0x0F;
+        }
+        self.len += 1;
     }
 
-    pub fn prefixed_bytes(&self, leaf: bool) -> Vec<u8> {
-        let mut prefixed: Vec<u8> = Vec::with_capacity(2 + self.data.len());
+    pub fn extend(&mut self, other: &Self) {
+        if self.len.is_multiple_of(2) {
+            self.data.extend_from_slice(&other.data);
+            self.len += other.len;
+            return;
+        }
 
-        let prefix = match self.data.len() % 2 {
-            0 => vec![0x00, 0x00],
-            _ => vec![0x01],
-        };
+        for i in 0..other.len {
+            self.push(other.at(i) as u8);
+        }
+    }
 
-        prefixed.extend(prefix);
-        prefixed.extend(&self.data);
-        if leaf {
-            prefixed[0] += 0x02
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
         }
 
-        let mut result = Vec::with_capacity(prefixed.len() / 2);
-        for i in (0..prefixed.len()).step_by(2) {
-            result.push((prefixed[i] << 4) + prefixed[i + 1]);
+        self.data.truncate(len.div_ceil(2));
+        if len % 2 == 1 {
+            *self.data.last_mut().unwrap() &= 0xF0;
         }
-        result
+        self.len = len;
     }
 
-    pub fn slice_to(&self, end: usize) -> Self {
+    /// Packs the nibbles back into bytes. Returns an error if there is an
+    /// odd number of nibbles, since the result wouldn't be a valid key.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if !self.len.is_multiple_of(2) {
+            return Err("odd number of nibbles".into());
+        }
+
+        Ok(self.data.clone())
+    }
+}
+
+impl<'a> NibbleSlice<'a> {
+    /// Views every nibble of `bytes`.
+    pub fn new(bytes: &'a [u8]) -> Self {
         Self {
-            data: self.data[..end].to_vec(),
+            data: bytes,
+            start: 0,
+            end: bytes.len() * 2,
         }
     }
 
-    pub fn slice_from(&self, start: usize) -> Self {
+    /// Views the first `len` nibbles of bytes in the packed layout.
+    pub fn from_packed(bytes: &'a [u8], len: usize) -> Self {
         Self {
-            data: self.data[start..].to_vec(),
+            data: bytes,
+            start: 0,
+            end: len,
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
     pub fn at(&self, index: usize) -> usize {
-        self.data[index] as usize
+        let i = self.start + index;
+        debug_assert!(i < self.end);
+        let byte = self.data[i / 2];
+        (if i.is_multiple_of(2) { byte >> 4 } else { byte & 0x0F }) as usize
     }
 
-    pub fn raw_bytes(&self) -> &[u8] {
-        &self.data
+    pub fn slice_from(&self, start: usize) -> Self {
+        Self {
+            data: self.data,
+            start: self.start + start,
+            end: self.end,
+        }
+    }
+
+    pub fn slice_to(&self, end: usize) -> Self {
+        Self {
+            data: self.data,
+            start: self.start,
+            end: self.start + end,
+        }
+    }
+
+    /// How many leading nibbles the two views have in common.
+    pub fn common_prefix_len(&self, other: &NibbleSlice) -> usize {
+        let max = self.len().min(other.len());
+
+        // When both views are byte-aligned the same way, whole bytes can be
+        // compared at once.
+        if self.start % 2 == other.start % 2 {
+            let mut i = 0;
+            if self.start % 2 == 1 {
+                if max == 0 || self.at(0) != other.at(0) {
+                    return 0;
+                }
+                i = 1;
+            }
+
+            let a = &self.data[(self.start + i) / 2..];
+            let b = &other.data[(other.start + i) / 2..];
+            let same = a.iter().zip(b).take((max - i) / 2).take_while(|(x, y)| x == y).count();
+            i += same * 2;
+            while i < max && self.at(i) == other.at(i) {
+                i += 1;
+            }
+            return i;
+        }
+
+        (0..max).take_while(|i| self.at(*i) == other.at(*i)).count()
+    }
+
+    pub fn to_nibbles(self) -> Nibbles {
+        if self.start.is_multiple_of(2) {
+            return Nibbles::from_packed(&self.data[self.start / 2..], self.len());
+        }
+
+        let mut nibbles = Nibbles {
+            data: Vec::with_capacity(self.len().div_ceil(2)),
+            len: 0,
+        };
+        for i in 0..self.len() {
+            nibbles.push(self.at(i) as u8);
+        }
+        nibbles
     }
 }
 
 impl Serialize for Nibbles {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer {
-        let mut bytes = Vec::with_capacity(self.data.len() / 2 + 1);
-        if self.data.len() % 2 == 0 {
+        let mut bytes = Vec::with_capacity(self.data.len() + 1);
+        if self.len.is_multiple_of(2) {
             bytes.push(0x00);
         } else {
             bytes.push(0x01);
         }
-        for i in (0..self.data.len()).step_by(2) {
-            let mut byte = self.data[i] << 4;
-            if i + 1 < self.data.len() {
-                byte += self.data[i + 1];
-            }
-            bytes.push(byte);
-        }
+        bytes.extend_from_slice(&self.data);
         serializer.serialize_bytes(&bytes)
     }
 }
@@ -114,31 +237,25 @@ impl Serialize for Nibbles {
 impl<'de> Deserialize<'de> for Nibbles {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where D: serde::Deserializer<'de> {
+        use serde::de::Error;
+
         let bytes = Vec::<u8>::deserialize(deserializer)?;
-        let mut data = Vec::with_capacity(bytes.len() * 2);
-        for byte in bytes[1..].iter() {
-            data.push(byte >> 4);
-            data.push(byte & 0x0F);
+        let (flag, data) = bytes.split_first().ok_or_else(|| D::Error::custom("missing nibbles flag"))?;
+        if *flag > 1 || (*flag == 1 && data.is_empty()) {
+            return Err(D::Error::custom(format!("invalid nibbles flag {flag:#04x}")));
         }
-
-        let data = if bytes[0] == 0x00 {
-            data
-        } else {
-            data[..data.len() - 1].to_vec()
-        };
-
-        Ok(Self {
-            data,
-        })
+        let len = data.len() * 2 - *flag as usize;
+        Ok(Self::from_packed(&bytes[1..], len))
     }
 }
 
+#[cfg(test)]
 macro_rules! nibbles {
     ( $( $x:expr ),* ) => {
         {
-            Nibbles {
-                data: vec![ $( $x ),*]
-            }
+            let mut nibbles = Nibbles::default();
+            $( nibbles.push($x); )*
+            nibbles
         }
     };
 }
@@ -161,23 +278,29 @@ mod tests {
     }
 
     #[test]
-    fn test_intersection() {
-        let nibbles1 = Nibbles::from_bytes(&[0x12, 0x34, 0x56, 0x78]);
-        let nibbles2 = Nibbles::from_bytes(&[0x12, 0x34, 0x66, 0x78]);
-        let result = nibbles1.intersection(&nibbles2);
-        assert_eq!(result.at(0), 0x01);
-        assert_eq!(result.at(1), 0x02);
-        assert_eq!(result.at(2), 0x03);
-        assert_eq!(result.at(3), 0x04);
-        assert_eq!(result.len(), 4);
+    fn test_common_prefix_len() {
+        let a = NibbleSlice::new(&[0x12, 0x34, 0x56, 0x78]);
+        let b = NibbleSlice::new(&[0x12, 0x34, 0x66, 0x78]);
+        assert_eq!(a.common_prefix_len(&b), 4);
+        assert_eq!(a.common_prefix_len(&a), 8);
+        assert_eq!(a.slice_to(3).common_prefix_len(&b), 3);
+
+        // Same and different alignments.
+        assert_eq!(a.slice_from(1).common_prefix_len(&b.slice_from(1)), 3);
+        assert_eq!(a.slice_from(5).common_prefix_len(&b.slice_from(5)), 3);
+        let c = NibbleSlice::new(&[0x01, 0x23, 0x45]);
+        assert_eq!(a.common_prefix_len(&c.slice_from(1)), 5);
+        assert_eq!(c.slice_from(1).common_prefix_len(&a), 5);
     }
 
     #[test]
     fn test_slicing() {
         let nibbles = Nibbles::from_bytes(&[0x12, 0x34, 0x56, 0x78]);
-        assert_eq!(nibbles.slice_to(4), nibbles![0x01, 0x02, 0x03, 0x04]);
-        assert_eq!(nibbles.slice_from(4), nibbles![0x05, 0x06, 0x07, 0x08]);
-        assert_eq!(nibbles.at(3), 0x04);
+        let slice = nibbles.as_slice();
+        assert_eq!(slice.slice_to(4).to_nibbles(), nibbles![0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(slice.slice_from(4).to_nibbles(), nibbles![0x05, 0x06, 0x07, 0x08]);
+        assert_eq!(slice.slice_from(1).slice_to(3).to_nibbles(), nibbles![0x02, 0x03, 0x04]);
+        assert_eq!(slice.slice_from(3).at(0), 0x04);
     }
 
     #[test]
@@ -190,6 +313,19 @@ mod tests {
         prefixed_bytes_test(&[0x01, 0x02, 0x03], &[0x31, 0x23], true);
     }
 
+    #[test]
+    fn test_to_bytes() {
+        let mut nibbles = Nibbles::from_bytes(&[0x12, 0x34]);
+        nibbles.extend(&Nibbles::from_bytes(&[0x56]));
+        assert_eq!(nibbles.to_bytes().unwrap(), vec![0x12, 0x34, 0x56]);
+        nibbles.push(0x07);
+        assert!(nibbles.to_bytes().is_err());
+        nibbles.extend(&Nibbles::from_bytes(&[0x89]));
+        assert_eq!(nibbles, nibbles![1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        nibbles.truncate(4);
+        assert_eq!(nibbles.to_bytes().unwrap(), vec![0x12, 0x34]);
+    }
+
     #[test]
     fn test_len() {
         let nibbles = Nibbles::from_bytes(&[0x12, 0x34, 0x56, 0x78]);
@@ -203,12 +339,19 @@ mod tests {
         assert_eq!(serialized, "[1,18,48]");
         let deserialized: Nibbles = serde_json::from_str(&serialized)?;
         assert_eq!(deserialized, nibbles);
+
+        for invalid in ["[]", "[2,18]", "[1]"] {
+            assert!(serde_json::from_str::<Nibbles>(invalid).is_err(), "{invalid}");
+        }
+        assert!(serde_json::from_str::<Nibbles>("[0]")?.is_empty());
         Ok(())
     }
 
     fn prefixed_bytes_test(data: &[u8], exp: &[u8], leaf: bool) {
-        let nibbles = Nibbles { data: data.to_vec() };
-        let prefixed = nibbles.prefixed_bytes(leaf);
-        assert_eq!(prefixed, exp);
+        let mut nibbles = Nibbles::default();
+        for nibble in data {
+            nibbles.push(*nibble);
+        }
+        assert_eq!(nibbles.prefixed_bytes(leaf), exp);
     }
-}
\ No newline at end of file
+}
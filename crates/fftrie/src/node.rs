@@ -1,12 +1,68 @@
 use std::fmt::Display;
+use std::ops::Deref;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::nibbles::Nibbles;
+use crate::view::Reader;
+
+/// A node's keccak hash, or its whole RLP encoding when that is shorter than
+/// 32 bytes and gets embedded in the parent instead. Either way it is kept
+/// inline, so hashes never allocate.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeHash {
+    bytes: [u8; 32],
+    len: u8,
+}
+
+impl NodeHash {
+    /// Panics if `bytes` is longer than 32 bytes.
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut hash = Self { bytes: [0; 32], len: bytes.len() as u8 };
+        hash.bytes[..bytes.len()].copy_from_slice(bytes);
+        hash
+    }
+
+    /// Whether this is an encoding short enough to be embedded in the parent
+    /// rather than an actual hash.
+    pub fn is_embedded(&self) -> bool {
+        self.len < 32
+    }
+}
+
+impl Deref for NodeHash {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl From<[u8; 32]> for NodeHash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self { bytes, len: 32 }
+    }
+}
+
+impl Serialize for NodeHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        if bytes.len() > 32 {
+            return Err(serde::de::Error::invalid_length(bytes.len(), &"at most 32 bytes"));
+        }
+        Ok(Self::new(&bytes))
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Meta {
-    pub hash: Option<Vec<u8>>,
+    pub hash: Option<NodeHash>,
 
     #[serde(skip_serializing, default = "default_as_false")]
     pub(super) dirty: bool,
@@ -25,6 +81,9 @@ impl Default for Meta {
     }
 }
 
+// Branches are by far the most common node, so boxing them to shrink the
+// enum would only add an allocation to most nodes.
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "type", content = "node")]
 pub enum Node {
@@ -44,82 +103,46 @@ impl Display for Node {
 }
 
 impl Node {
+    /// Decodes a record written by `to_writer`. Records that are truncated or
+    /// otherwise malformed are an error.
     pub fn from_slice(slice: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut n: usize = 0;
+        let mut reader = Reader::new(slice);
 
-        let mut node = match slice[n] {
+        let mut node = match reader.take(1)?[0] {
             0 => {
-                n += 1;
-
-                let mut children = [0i64; 16];
-
-                for i in 0..16 {
-                    children[i] = i64::from_be_bytes(slice[n..n + 8].try_into().unwrap());
-                    n += 8;
+                let mut children: [NodeRef; 16] = Default::default();
+                for child in children.iter_mut() {
+                    *child = NodeRef::read(&mut reader)?;
                 }
 
-                let value_len = u16::from_be_bytes(slice[n..n + 2].try_into().unwrap()) as usize;
-                n += 2;
-
-                let value = if value_len > 0 {
-                    Some(slice[n..n + value_len].to_vec())
-                } else {
-                    None
-                };
-
-                n += value_len;
-
+                let value = reader.value()?;
                 Node::Branch(Branch {
                     children,
-                    value,
+                    value: (!value.is_empty()).then(|| value.to_vec()),
                     meta: Meta::default(),
                 })
             }
-            1 => {
-                n += 1;
-
-                let path_len = slice[n] as usize;
-                n += 1;
-
-                let path = Nibbles::from_raw_bytes(&slice[n..n + path_len]);
-                n += path_len;
-
-                let value_len = u16::from_be_bytes(slice[n..n + 2].try_into().unwrap()) as usize;
-                n += 2;
-
-                let value = slice[n..n + value_len].to_vec();
-                n += value_len;
-
-                Node::Leaf(Leaf {
-                    path,
-                    value,
-                    meta: Meta::default(),
-                })
-            }
-            2 => {
-                n += 1;
-
-                let path_len = slice[n] as usize;
-                n += 1;
-
-                let path = Nibbles::from_raw_bytes(&slice[n..n + path_len]);
-                n += path_len;
-
-                let child = i64::from_be_bytes(slice[n..n + 8].try_into().unwrap());
-                n += 8;
-
-                Node::Extension(Extension {
-                    path,
-                    child,
-                    meta: Meta::default(),
-                })
-            }
-            _ => panic!("Invalid node type"),
+            1 => Node::Leaf(Leaf {
+                path: reader.path()?.to_nibbles(),
+                value: reader.value()?.to_vec(),
+                meta: Meta::default(),
+            }),
+            2 => Node::Extension(Extension {
+                path: reader.path()?.to_nibbles(),
+                child: NodeRef::read(&mut reader)?,
+                meta: Meta::default(),
+            }),
+            _ => return Err("invalid node type".into()),
         };
 
-        let hash = slice[n..n + 32].to_vec();
-        n += 32;
-        node.set_hash(hash);
+        // Nodes whose encoding is shorter than 32 bytes are embedded in their
+        // parent rather than hashed, so the "hash" is whatever is left.
+        let hash = reader.rest();
+        if hash.len() > 32 {
+            return Err("node hash too long".into());
+        }
+        node.set_hash(NodeHash::new(hash));
+        node.set_dirty(false);
         node.set_committed(true);
         Ok(node)
     }
@@ -129,8 +152,8 @@ impl Node {
             Node::Branch(branch) => {
                 writer.write_all(&[0])?;
 
-                for i in 0..16 {
-                    writer.write_all(&branch.children[i].to_be_bytes())?;
+                for child in &branch.children {
+                    child.to_writer(writer)?;
                 }
 
                 match &branch.value {
@@ -139,7 +162,7 @@ impl Node {
                         writer.write_all(value)?;
                     }
                     None => {
-                        writer.write_all(&0u64.to_be_bytes())?;
+                        writer.write_all(&0u16.to_be_bytes())?;
                     }
                 }
             }
@@ -147,7 +170,7 @@ impl Node {
                 writer.write_all(&[1])?;
 
                 writer.write_all(&(leaf.path.len() as u8).to_be_bytes())?;
-                writer.write_all(&leaf.path.raw_bytes())?;
+                writer.write_all(leaf.path.packed())?;
 
                 writer.write_all(&(leaf.value.len() as u16).to_be_bytes())?;
                 writer.write_all(&leaf.value)?;
@@ -156,9 +179,9 @@ impl Node {
                 writer.write_all(&[2])?;
 
                 writer.write_all(&(extension.path.len() as u8).to_be_bytes())?;
-                writer.write_all(&extension.path.raw_bytes())?;
+                writer.write_all(extension.path.packed())?;
 
-                writer.write_all(&extension.child.to_be_bytes())?;
+                extension.child.to_writer(writer)?;
             }
         }
 
@@ -172,15 +195,29 @@ impl Node {
         Ok(())
     }
 
-    pub fn hash(&self) -> Option<Vec<u8>> {
+    /// Roughly how many bytes of memory the node takes up, heap included.
+    pub fn approx_size(&self) -> usize {
+        let heap = match self {
+            Node::Branch(branch) => {
+                let inline: usize = branch.children.iter().map(NodeRef::inline_size).sum();
+                inline + branch.value.as_ref().map_or(0, |v| v.len())
+            }
+            Node::Leaf(leaf) => leaf.path.packed().len() + leaf.value.len(),
+            Node::Extension(extension) => extension.path.packed().len() + extension.child.inline_size(),
+        };
+
+        std::mem::size_of::<Node>() + heap
+    }
+
+    pub fn hash(&self) -> Option<NodeHash> {
         match self {
-            Node::Branch(branch) => branch.meta.hash.clone(),
-            Node::Leaf(leaf) => leaf.meta.hash.clone(),
-            Node::Extension(extension) => extension.meta.hash.clone(),
+            Node::Branch(branch) => branch.meta.hash,
+            Node::Leaf(leaf) => leaf.meta.hash,
+            Node::Extension(extension) => extension.meta.hash,
         }
     }
 
-    pub fn set_hash(&mut self, hash: Vec<u8>) {
+    pub fn set_hash(&mut self, hash: NodeHash) {
         match self {
             Node::Branch(branch) => branch.meta.hash = Some(hash),
             Node::Leaf(leaf) => leaf.meta.hash = Some(hash),
@@ -221,9 +258,76 @@ impl Node {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// A reference from a node to one of its children.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub enum NodeRef {
+    #[default]
+    Empty,
+    /// A node in the trie's in-memory arena, not written anywhere yet. Within
+    /// a `Store::put_batch`, an earlier node of the same batch.
+    Dirty(u32),
+    /// A node written to the store at this offset.
+    Stored(u64),
+    /// A node whose encoding is short enough to be embedded in its parent.
+    Inline(Box<Node>),
+}
+
+impl NodeRef {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, NodeRef::Empty)
+    }
+
+    /// The store offset, for stored children.
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            NodeRef::Stored(offset) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    fn inline_size(&self) -> usize {
+        match self {
+            NodeRef::Inline(node) => node.approx_size(),
+            _ => 0,
+        }
+    }
+
+    fn read(reader: &mut Reader) -> Result<Self, Box<dyn std::error::Error>> {
+        match reader.take(1)?[0] {
+            0 => Ok(NodeRef::Empty),
+            1 => Ok(NodeRef::Stored(u64::from_be_bytes(reader.take(8)?.try_into().unwrap()))),
+            2 => {
+                let len = reader.u16()?;
+                Ok(NodeRef::Inline(Box::new(Node::from_slice(reader.take(len)?)?)))
+            }
+            _ => Err("invalid node reference".into()),
+        }
+    }
+
+    fn to_writer(&self, writer: &mut dyn std::io::Write) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            NodeRef::Empty => writer.write_all(&[0])?,
+            NodeRef::Stored(offset) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&offset.to_be_bytes())?;
+            }
+            NodeRef::Inline(node) => {
+                let mut buf = Vec::new();
+                node.to_writer(&mut buf)?;
+                writer.write_all(&[2])?;
+                writer.write_all(&(buf.len() as u16).to_be_bytes())?;
+                writer.write_all(&buf)?;
+            }
+            NodeRef::Dirty(_) => return Err("cannot encode a reference to a dirty node".into()),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Branch {
-    pub children: [i64; 16],
+    pub children: [NodeRef; 16],
     pub value: Option<Vec<u8>>,
     pub meta: Meta,
 }
@@ -234,17 +338,7 @@ impl Branch {
     }
 }
 
-impl Default for Branch {
-    fn default() -> Self {
-        Self {
-            children: [0; 16],
-            value: None,
-            meta: Meta::default(),
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Leaf {
     pub path: Nibbles,
     pub value: Vec<u8>,
@@ -261,25 +355,15 @@ impl Leaf {
     }
 }
 
-impl Default for Leaf {
-    fn default() -> Self {
-        Self {
-            path: Nibbles::default(),
-            value: Vec::new(),
-            meta: Meta::default(),
-        }
-    }
-}
-
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Extension {
     pub path: Nibbles,
-    pub child: i64,
+    pub child: NodeRef,
     pub meta: Meta,
 }
 
 impl Extension {
-    pub fn new(path: Nibbles, child: i64) -> Self {
+    pub fn new(path: Nibbles, child: NodeRef) -> Self {
         Self {
             path,
             child,
@@ -288,16 +372,6 @@ impl Extension {
     }
 }
 
-impl Default for Extension {
-    fn default() -> Self {
-        Self {
-            path: Nibbles::default(),
-            child: 0,
-            meta: Meta::default(),
-        }
-    }
-}
-
 // https://github.com/serde-rs/serde/issues/368
 fn default_as_true() -> bool {
     true
@@ -0,0 +1,813 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::nibbles::Nibbles;
+use crate::node::{Node, NodeRef};
+use crate::store::{resolve_batch_refs, Store};
+
+// The nodes a commit overwrote or freed, with what each slot held before it.
+// Applying them newest first takes the store back one version.
+type ReverseDiff = Vec<(u64, Option<Node>)>;
+
+// A stored slot: the path it sits at and its node.
+type Entry = (Vec<u8>, Node);
+
+// The file starts with the number of committed versions, followed by chunks
+// of `1 << class` bytes. Each chunk starts with its class and the slot it
+// holds, or `FREE`, then the slot's path key and node record.
+const FILE_HEADER: u64 = 8;
+const CHUNK_HEADER: usize = 1 + 8;
+const FREE: u64 = u64::MAX;
+const MIN_CLASS: u8 = 6;
+
+// The journal starts with the version it rolls back to and the size the file
+// had then, followed by what each region of the file held before it was
+// overwritten: its position, length and old contents.
+const JOURNAL_HEADER: usize = 8 + 8;
+
+// Where a slot's node lives in the file.
+#[derive(Clone, Copy)]
+struct Chunk {
+    pos: u64,
+    class: u8,
+}
+
+/// A store that keys nodes by their position in the trie, the owner followed
+/// by the nibble path from the root, instead of appending them. Each path is
+/// given a slot the first time a node is written there, and later versions of
+/// that node overwrite it in place. Paths that a batch leaves out of the trie,
+/// through deletes or collapsed branches, have their slots freed, so the file
+/// only ever holds the latest trie and its size follows the trie rather than
+/// the number of commits.
+///
+/// Slots live in power of two sized chunks of the file. A node that outgrows
+/// its chunk moves to a bigger one and leaves the old one free for reuse.
+/// Before a batch overwrites anything, the old contents are saved to a journal
+/// next to the store, which is cleared once the commit is durable. Opening a
+/// store with a journal left over rolls the file back to the last commit.
+///
+/// The root always lands in the same slot. Older roots are read with
+/// `at_version` through the reverse diffs of the last `retain` commits, which
+/// are kept in a file per commit next to the store.
+///
+/// Nodes only have a path as part of a batch written from the root down, so
+/// single `put`s are refused. Offsets are overwritten, so wrappers that keep
+/// track of them check `Store::overwrites_in_place`.
+pub struct PathStore {
+    path: PathBuf,
+    file: File,
+    journal: File,
+    // The file size the journal rolls back to, once something was written
+    // since the last commit.
+    journal_base: Option<u64>,
+    owner: Vec<u8>,
+    keys: HashMap<Vec<u8>, u64>,
+    slots: Vec<Option<Chunk>>,
+    free_slots: Vec<u64>,
+    free: Vec<Vec<u64>>,
+    end: u64,
+    retain: usize,
+    version: u64,
+    pending: ReverseDiff,
+}
+
+impl PathStore {
+    /// Creates an empty store at `path` for the trie belonging to `owner`,
+    /// e.g. an account hash for a storage trie, or nothing for the account
+    /// trie.
+    pub fn new(path: &str, owner: &[u8], retain: usize) -> Result<Self, Box<dyn Error>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+        file.write_all(&0u64.to_be_bytes())?;
+        let journal = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(journal_path(Path::new(path)))?;
+        let store = Self::with_files(Path::new(path), file, journal, owner, retain);
+        store.remove_stale_history()?;
+        Ok(store)
+    }
+
+    /// Opens an existing store, rolling back anything written after its last
+    /// commit and finding its slots by scanning the chunk headers.
+    pub fn open(path: &str, owner: &[u8], retain: usize) -> Result<Self, Box<dyn Error>> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut version = [0; 8];
+        file.read_exact(&mut version).map_err(|_| "not a path store")?;
+        let version = u64::from_be_bytes(version);
+        let mut journal = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(journal_path(Path::new(path)))?;
+        roll_back(&mut file, &mut journal, version)?;
+
+        let len = file.metadata()?.len();
+        let mut store = Self::with_files(Path::new(path), file, journal, owner, retain);
+        store.version = version;
+
+        let mut reader = BufReader::new(&store.file);
+        reader.seek(io::SeekFrom::Start(FILE_HEADER))?;
+        let mut pos = FILE_HEADER;
+        while pos + CHUNK_HEADER as u64 <= len {
+            let mut header = [0; CHUNK_HEADER];
+            reader.read_exact(&mut header)?;
+            let class = header[0];
+            if !(MIN_CLASS..64).contains(&class) {
+                return Err("corrupt chunk header".into());
+            }
+            let chunk = Chunk { pos, class };
+            let slot = u64::from_be_bytes(header[1..].try_into().unwrap());
+            let mut read = CHUNK_HEADER;
+            if slot == FREE {
+                free_chunk(&mut store.free, chunk);
+            } else {
+                let mut key_len = [0; 2];
+                reader.read_exact(&mut key_len)?;
+                let mut key = vec![0; u16::from_be_bytes(key_len) as usize];
+                reader.read_exact(&mut key)?;
+                read += 2 + key.len();
+
+                store.keys.insert(key, slot);
+                if store.slots.len() <= slot as usize {
+                    store.slots.resize(slot as usize + 1, None);
+                }
+                store.slots[slot as usize] = Some(chunk);
+            }
+            pos += 1 << class;
+            reader.seek_relative((1i64 << class) - read as i64)?;
+        }
+        drop(reader);
+        store.end = store.end.max(pos);
+        store.free_slots = (0..store.slots.len() as u64).rev().filter(|slot| store.slots[*slot as usize].is_none()).collect();
+
+        store.remove_stale_history()?;
+        Ok(store)
+    }
+
+    fn with_files(path: &Path, file: File, journal: File, owner: &[u8], retain: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            file,
+            journal,
+            journal_base: None,
+            owner: owner.to_vec(),
+            keys: HashMap::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            free: Vec::new(),
+            end: FILE_HEADER,
+            retain,
+            version: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The number of committed roots so far.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The number of paths holding a node.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The size of the file holding the slots.
+    pub fn file_size(&self) -> u64 {
+        self.end
+    }
+
+    /// A read-only view of the store as it was after `version` commits,
+    /// undoing the commits since as well as anything written after the last
+    /// one. Only the slots those touched are read from the history; the rest
+    /// are read from the store as it is.
+    pub fn at_version(&self, version: u64) -> Result<PathSnapshot<'_>, Box<dyn Error>> {
+        let oldest = self.version.saturating_sub(self.retain as u64);
+        if version < oldest || version > self.version {
+            return Err("version is not retained".into());
+        }
+
+        // Each slot takes what it held before the first write after
+        // `version`.
+        let mut overlay = HashMap::new();
+        for undone in version + 1..=self.version {
+            for (slot, node) in read_history(&self.history_path(undone))? {
+                overlay.entry(slot).or_insert(node);
+            }
+        }
+        for (slot, node) in &self.pending {
+            overlay.entry(*slot).or_insert_with(|| node.clone());
+        }
+
+        Ok(PathSnapshot { store: self, overlay })
+    }
+
+    fn key(&self, path: &Nibbles) -> Vec<u8> {
+        let mut key = self.owner.clone();
+        key.extend((0..path.len()).map(|i| path.at(i) as u8));
+        key
+    }
+
+    fn read_slot(&self, slot: u64) -> Result<Option<Node>, Box<dyn Error>> {
+        Ok(self.read_entry(slot)?.map(|(_, node)| node))
+    }
+
+    // The path key and node held by `slot`.
+    fn read_entry(&self, slot: u64) -> Result<Option<Entry>, Box<dyn Error>> {
+        let Some(Some(chunk)) = self.slots.get(slot as usize).copied() else {
+            return Ok(None);
+        };
+
+        let mut data = vec![0; 1 << chunk.class];
+        let mut file = &self.file;
+        file.seek(io::SeekFrom::Start(chunk.pos))?;
+        // The last chunk may end before its full size.
+        let len = file.read(&mut data)?;
+        let (key, record) = read_payload(&data[CHUNK_HEADER.min(len)..len])?;
+        Ok(Some((key.to_vec(), Node::from_slice(record)?)))
+    }
+
+    // Places `node` in the chunk for `slot`, moving it to a bigger one if it
+    // doesn't fit, and adds what has to be written to `writes`.
+    fn place(&mut self, slot: u64, key: &[u8], node: &Node, writes: &mut Vec<(u64, Vec<u8>)>) -> Result<(), Box<dyn Error>> {
+        let mut record = Vec::new();
+        node.to_writer(&mut record)?;
+        let size = CHUNK_HEADER + 2 + key.len() + 4 + record.len();
+        let class = (size.next_power_of_two().trailing_zeros() as u8).max(MIN_CLASS);
+
+        let current = self.slots.get(slot as usize).copied().flatten();
+        let chunk = match current {
+            Some(chunk) if chunk.class >= class => chunk,
+            _ => {
+                if let Some(old) = current {
+                    writes.push((old.pos + 1, FREE.to_be_bytes().to_vec()));
+                    free_chunk(&mut self.free, old);
+                }
+                self.allocate(class)
+            }
+        };
+
+        let mut data = Vec::with_capacity(size);
+        data.push(chunk.class);
+        data.extend_from_slice(&slot.to_be_bytes());
+        data.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        data.extend_from_slice(key);
+        data.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        data.extend_from_slice(&record);
+        writes.push((chunk.pos, data));
+
+        if self.slots.len() <= slot as usize {
+            self.slots.resize(slot as usize + 1, None);
+        }
+        self.slots[slot as usize] = Some(chunk);
+        Ok(())
+    }
+
+    // Frees the slot of a path that is no longer in the trie.
+    fn release(&mut self, slot: u64, key: &[u8], writes: &mut Vec<(u64, Vec<u8>)>) {
+        if let Some(chunk) = self.slots[slot as usize].take() {
+            writes.push((chunk.pos + 1, FREE.to_be_bytes().to_vec()));
+            free_chunk(&mut self.free, chunk);
+        }
+        self.keys.remove(key);
+        self.free_slots.push(slot);
+    }
+
+    fn allocate(&mut self, class: u8) -> Chunk {
+        if let Some(pos) = self.free.get_mut(class as usize).and_then(Vec::pop) {
+            return Chunk { pos, class };
+        }
+
+        let chunk = Chunk { pos: self.end, class };
+        self.end += 1 << class;
+        chunk
+    }
+
+    // Saves what `writes` are about to overwrite to the journal and makes it
+    // durable. Regions past the file's size at the last commit are dropped
+    // by truncating it instead.
+    fn journal(&mut self, writes: &[(u64, Vec<u8>)], len: u64) -> io::Result<()> {
+        let mut data = Vec::new();
+        let base = match self.journal_base {
+            Some(base) => base,
+            None => {
+                data.extend_from_slice(&self.version.to_be_bytes());
+                data.extend_from_slice(&len.to_be_bytes());
+                self.journal_base = Some(len);
+                len
+            }
+        };
+
+        for (pos, new) in writes.iter().filter(|(pos, _)| *pos < base) {
+            let mut old = vec![0; new.len().min((base - pos) as usize)];
+            let mut file = &self.file;
+            file.seek(io::SeekFrom::Start(*pos))?;
+            file.read_exact(&mut old)?;
+            data.extend_from_slice(&pos.to_be_bytes());
+            data.extend_from_slice(&(old.len() as u32).to_be_bytes());
+            data.extend_from_slice(&old);
+        }
+
+        self.journal.seek(io::SeekFrom::End(0))?;
+        self.journal.write_all(&data)?;
+        self.journal.sync_data()
+    }
+
+    fn write_at(&mut self, pos: u64, data: &[u8]) -> io::Result<()> {
+        write_at(&mut self.file, pos, data)
+    }
+
+    fn history_path(&self, version: u64) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".history.{}", version));
+        PathBuf::from(name)
+    }
+
+    // Deletes the history files of versions that are no longer retained, or
+    // that were never committed.
+    fn remove_stale_history(&self) -> io::Result<()> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = match self.path.file_name() {
+            Some(name) => format!("{}.history.", name.to_string_lossy()),
+            None => return Ok(()),
+        };
+
+        let oldest = self.version.saturating_sub(self.retain as u64);
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let version = name.to_string_lossy().strip_prefix(&prefix).and_then(|v| v.parse::<u64>().ok());
+            if version.is_some_and(|version| version <= oldest || version > self.version) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Store for PathStore {
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        self.read_slot(offset)?.ok_or("node not found".into())
+    }
+
+    fn put(&mut self, _node: Node) -> Result<u64, Box<dyn Error>> {
+        Err("path store only takes whole batches".into())
+    }
+
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        // The root comes last, so paths are handed out from the end of the
+        // batch down to the children it points at.
+        let mut paths: Vec<Option<Nibbles>> = vec![None; nodes.len()];
+        if let Some(root) = paths.last_mut() {
+            *root = Some(Nibbles::default());
+        }
+        for (i, node) in nodes.iter().enumerate().rev() {
+            let path = paths[i].clone().ok_or("batch node is not reachable from the root")?;
+            for (index, child_path) in batch_children(node, &path) {
+                if let Some(slot) = paths.get_mut(index as usize) {
+                    *slot = Some(child_path);
+                }
+            }
+        }
+
+        // Nothing is written until every node has a chunk, so that all of it
+        // can go into the journal first.
+        let len = self.end;
+        let mut writes = Vec::new();
+        let mut offsets = Vec::with_capacity(nodes.len());
+        let mut kept = HashSet::new();
+        let mut replaced = Vec::new();
+        for (mut node, path) in nodes.into_iter().zip(paths) {
+            resolve_batch_refs(&mut node, &offsets)?;
+            let key = self.key(&path.unwrap());
+            let slot = match self.keys.get(&key) {
+                Some(slot) => *slot,
+                None => {
+                    let slot = self.free_slots.pop().unwrap_or(self.slots.len() as u64);
+                    self.keys.insert(key.clone(), slot);
+                    slot
+                }
+            };
+
+            let old = self.read_slot(slot)?;
+            self.place(slot, &key, &node, &mut writes)?;
+            kept.insert(slot);
+            kept.extend(stored_children(&node));
+            replaced.extend(old.iter().flat_map(stored_children));
+            self.pending.push((slot, old));
+            offsets.push(slot);
+        }
+
+        // Anything the replaced nodes pointed at that the batch doesn't keep
+        // has dropped out of the trie, along with everything below it.
+        let mut vanished: Vec<u64> = replaced.into_iter().filter(|slot| !kept.contains(slot)).collect();
+        while let Some(slot) = vanished.pop() {
+            if !kept.insert(slot) {
+                continue;
+            }
+            let Some((key, old)) = self.read_entry(slot)? else {
+                continue;
+            };
+            vanished.extend(stored_children(&old).into_iter().filter(|slot| !kept.contains(slot)));
+            self.release(slot, &key, &mut writes);
+            self.pending.push((slot, Some(old)));
+        }
+
+        self.journal(&writes, len)?;
+        for (pos, data) in &writes {
+            self.write_at(*pos, data)?;
+        }
+        if self.end > len {
+            self.file.set_len(self.end)?;
+        }
+        Ok(offsets)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    fn overwrites_in_place(&self) -> bool {
+        true
+    }
+
+    // The slots are made durable before the version that commits them, and
+    // the journal is only cleared after it.
+    fn commit_root(&mut self, _offset: u64) -> Result<(), Box<dyn Error>> {
+        self.file.sync_data()?;
+        self.version += 1;
+        if self.retain > 0 {
+            write_history(&self.history_path(self.version), &self.pending)?;
+        }
+        self.pending.clear();
+        self.write_at(0, &self.version.to_be_bytes())?;
+        self.file.sync_data()?;
+
+        self.journal.set_len(0)?;
+        self.journal_base = None;
+        self.remove_stale_history()?;
+        Ok(())
+    }
+}
+
+fn journal_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".journal");
+    PathBuf::from(name)
+}
+
+// Restores what the journal saved, if it was started at `version`, which
+// takes the file back to that commit. A journal from an earlier version
+// belongs to a commit that completed, and is just cleared.
+fn roll_back(file: &mut File, journal: &mut File, version: u64) -> Result<(), Box<dyn Error>> {
+    let mut data = Vec::new();
+    journal.read_to_end(&mut data)?;
+    if data.len() >= JOURNAL_HEADER && u64::from_be_bytes(data[..8].try_into().unwrap()) == version {
+        let len = u64::from_be_bytes(data[8..16].try_into().unwrap());
+        let mut entries = Vec::new();
+        let mut pos = JOURNAL_HEADER;
+        // A torn final entry was never followed by its write.
+        while let Some(entry) = data.get(pos..pos + 12) {
+            let at = u64::from_be_bytes(entry[..8].try_into().unwrap());
+            let size = u32::from_be_bytes(entry[8..].try_into().unwrap()) as usize;
+            let Some(old) = data.get(pos + 12..pos + 12 + size) else {
+                break;
+            };
+            entries.push((at, old));
+            pos += 12 + size;
+        }
+
+        // Older contents come first, so they're restored last.
+        for (at, old) in entries.into_iter().rev() {
+            write_at(file, at, old)?;
+        }
+        file.set_len(len)?;
+        file.sync_data()?;
+    }
+
+    journal.set_len(0)?;
+    journal.sync_data()?;
+    Ok(())
+}
+
+fn write_at(file: &mut File, pos: u64, data: &[u8]) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(pos))?;
+    file.write_all(data)
+}
+
+fn free_chunk(free: &mut Vec<Vec<u64>>, chunk: Chunk) {
+    if free.len() <= chunk.class as usize {
+        free.resize(chunk.class as usize + 1, Vec::new());
+    }
+    free[chunk.class as usize].push(chunk.pos);
+}
+
+// The slots of the children of `node` that are stored apart from it.
+fn stored_children(node: &Node) -> Vec<u64> {
+    match node {
+        Node::Branch(branch) => branch.children.iter().filter_map(NodeRef::offset).collect(),
+        Node::Extension(ext) => ext.child.offset().into_iter().collect(),
+        Node::Leaf(_) => Vec::new(),
+    }
+}
+
+// A chunk's path key and node record.
+type Payload<'a> = (&'a [u8], &'a [u8]);
+
+fn read_payload(data: &[u8]) -> Result<Payload<'_>, Box<dyn Error>> {
+    let truncated = || "truncated chunk";
+    let key_len = u16::from_be_bytes(data.get(..2).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+    let key = data.get(2..2 + key_len).ok_or_else(truncated)?;
+    let rest = &data[2 + key_len..];
+    let record_len = u32::from_be_bytes(rest.get(..4).ok_or_else(truncated)?.try_into().unwrap()) as usize;
+    let record = rest.get(4..4 + record_len).ok_or_else(truncated)?;
+    Ok((key, record))
+}
+
+// History files hold a reverse diff as a list of slots, each followed by
+// whether it held a node and that node's record.
+fn write_history(path: &Path, diff: &ReverseDiff) -> Result<(), Box<dyn Error>> {
+    let mut data = Vec::new();
+    let mut record = Vec::new();
+    for (slot, node) in diff {
+        data.extend_from_slice(&slot.to_be_bytes());
+        match node {
+            Some(node) => {
+                record.clear();
+                node.to_writer(&mut record)?;
+                data.push(1);
+                data.extend_from_slice(&(record.len() as u32).to_be_bytes());
+                data.extend_from_slice(&record);
+            }
+            None => data.push(0),
+        }
+    }
+    let mut file = File::create(path)?;
+    file.write_all(&data)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+fn read_history(path: &Path) -> Result<ReverseDiff, Box<dyn Error>> {
+    let data = std::fs::read(path)?;
+    let mut diff = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let entry = data.get(pos..pos + 9).ok_or("truncated history")?;
+        let slot = u64::from_be_bytes(entry[..8].try_into().unwrap());
+        pos += 9;
+        if entry[8] == 0 {
+            diff.push((slot, None));
+            continue;
+        }
+
+        let len = u32::from_be_bytes(data.get(pos..pos + 4).ok_or("truncated history")?.try_into().unwrap()) as usize;
+        let record = data.get(pos + 4..pos + 4 + len).ok_or("truncated history")?;
+        diff.push((slot, Some(Node::from_slice(record)?)));
+        pos += 4 + len;
+    }
+    Ok(diff)
+}
+
+// The children of `node` within its batch, with their paths given that `node`
+// is at `path`.
+fn batch_children(node: &Node, path: &Nibbles) -> Vec<(u32, Nibbles)> {
+    let mut children = Vec::new();
+    match node {
+        Node::Branch(branch) => {
+            for (nibble, child) in branch.children.iter().enumerate() {
+                if let NodeRef::Dirty(index) = child {
+                    let mut child_path = path.clone();
+                    child_path.push(nibble as u8);
+                    children.push((*index, child_path));
+                }
+            }
+        }
+        Node::Extension(ext) => {
+            if let NodeRef::Dirty(index) = ext.child {
+                let mut child_path = path.clone();
+                child_path.extend(&ext.path);
+                children.push((index, child_path));
+            }
+        }
+        Node::Leaf(_) => {}
+    }
+    children
+}
+
+/// A read-only view of a `PathStore` at an older version. It reads through
+/// the store, which can't be written to while the view is around.
+pub struct PathSnapshot<'a> {
+    store: &'a PathStore,
+    overlay: HashMap<u64, Option<Node>>,
+}
+
+impl Store for PathSnapshot<'_> {
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        let node = match self.overlay.get(&offset) {
+            Some(node) => node.clone(),
+            None => self.store.read_slot(offset)?,
+        };
+        node.ok_or("node not found".into())
+    }
+
+    fn put(&mut self, _node: Node) -> Result<u64, Box<dyn Error>> {
+        Err("snapshot is read-only".into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::Trie;
+
+    use super::*;
+
+    #[test]
+    fn test_overwrites_in_place() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-pathdb-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let store = Rc::new(RefCell::new(PathStore::new(&path, b"", 2)?));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        let mut results = Vec::new();
+        for i in 0..4u8 {
+            for j in 0..16u8 {
+                trie.insert(&[j << 4], &[i; 40])?;
+            }
+            results.push(trie.commit()?);
+        }
+
+        // Every commit rewrote the same paths: the root and its 16 leaves,
+        // without the file growing past the first commit's.
+        assert_eq!(store.borrow().version(), 4);
+        assert_eq!(store.borrow().len(), 17);
+        assert!(results.iter().all(|r| r.root_offset == results[0].root_offset));
+        assert_eq!(trie.get(&[5 << 4])?, vec![3; 40]);
+        assert!(store.borrow_mut().put(Node::Leaf(Default::default())).is_err());
+        let size = store.borrow().file_size();
+        assert_eq!(std::fs::metadata(&path)?.len(), size);
+
+        // Older roots come back from the reverse diffs, within the window.
+        {
+            let store = store.borrow();
+            let mut old = Trie::new(Rc::new(RefCell::new(store.at_version(2)?)), Some(results[1].root_offset));
+            assert_eq!(old.get(&[5 << 4])?, vec![1; 40]);
+            assert_eq!(old.calculate_root()?, results[1].root_hash);
+            assert!(store.at_version(1).is_err());
+        }
+
+        // A node that outgrows its chunk moves, and its old chunk is reused.
+        trie.insert(&[6 << 4], &[9; 200])?;
+        trie.insert(&[7 << 4], &[9; 200])?;
+        let grown = trie.commit()?;
+        let size = store.borrow().file_size();
+        trie.insert(&[0x55], &[9; 40])?;
+        let split = trie.commit()?;
+        assert_eq!(store.borrow().file_size(), size);
+
+        // Reopening finds every slot, and only the retained history.
+        let version = store.borrow().version();
+        drop(trie);
+        drop(store);
+        let store = Rc::new(RefCell::new(PathStore::open(&path, b"", 2)?));
+        assert_eq!(store.borrow().version(), version);
+        assert_eq!(store.borrow().len(), 19);
+        assert!(!Path::new(&format!("{}.history.{}", path, version - 2)).exists());
+        let mut trie = Trie::new(Rc::clone(&store), Some(split.root_offset));
+        assert_eq!(trie.get(&[0x55])?, vec![9; 40]);
+        assert_eq!(trie.calculate_root()?, split.root_hash);
+
+        // Uncommitted writes are undone as well.
+        let leaf = store.borrow_mut().get(split.root_offset + 1)?;
+        store.borrow_mut().put_batch(vec![leaf])?;
+        {
+            let store = store.borrow();
+            let mut old = Trie::new(Rc::new(RefCell::new(store.at_version(version)?)), Some(split.root_offset));
+            assert_eq!(old.calculate_root()?, split.root_hash);
+            let mut old = Trie::new(Rc::new(RefCell::new(store.at_version(version - 1)?)), Some(grown.root_offset));
+            assert_eq!(old.get(&[5 << 4])?, vec![3; 40]);
+            assert!(old.get(&[0x55]).is_err());
+            assert_eq!(old.calculate_root()?, grown.root_hash);
+        }
+
+        // Reopening after the uncommitted write rolls it back.
+        drop(store);
+        let store = Rc::new(RefCell::new(PathStore::open(&path, b"", 2)?));
+        let mut trie = Trie::new(Rc::clone(&store), Some(split.root_offset));
+        assert_eq!(trie.calculate_root()?, split.root_hash);
+        assert_eq!(std::fs::metadata(format!("{}.journal", path))?.len(), 0);
+
+        for version in version - 1..=version {
+            std::fs::remove_file(format!("{}.history.{}", path, version))?;
+        }
+        std::fs::remove_file(format!("{}.journal", path))?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_vanished_paths_are_freed() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-pathdb-freed-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let store = Rc::new(RefCell::new(PathStore::new(&path, b"", 1)?));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+
+        // Keys are added and deleted in turn, which splits and collapses the
+        // same branches over and over.
+        let mut sizes = Vec::new();
+        let mut root = 0;
+        for round in 0..4u8 {
+            for i in 0..32u8 {
+                trie.insert(&[i, 0], &[round; 40])?;
+                trie.insert(&[i, 1], &[round; 40])?;
+            }
+            trie.commit()?;
+            let full = store.borrow().len();
+            for i in 0..32u8 {
+                trie.remove(&[i, 1])?;
+            }
+            let result = trie.commit()?;
+            root = result.root_offset;
+            assert!(store.borrow().len() < full);
+            sizes.push(store.borrow().file_size());
+
+            let report = crate::check(&mut *store.borrow_mut(), result.root_offset);
+            assert!(report.is_ok(), "{:?}", report.problems);
+            assert_eq!(trie.iter().count(), 32);
+        }
+        assert!(sizes.iter().all(|size| *size == sizes[0]), "{:?}", sizes);
+
+        // The freed slots are still there for the version before.
+        let version = store.borrow().version();
+        let len = store.borrow().len();
+        let root_hash = trie.calculate_root()?;
+
+        // Writes that never get committed, as if the process died before the
+        // commit, moving and freeing chunks on the way.
+        for i in 0..16u8 {
+            trie.insert(&[i, 2], &[9; 300])?;
+            trie.remove(&[i + 16, 0])?;
+        }
+        trie.calculate_root()?;
+        let node = trie.get_node(&trie.root)?;
+        trie.write_node(&mut node.clone(), &mut Vec::new())?;
+        drop(trie);
+        {
+            let store = store.borrow();
+            let old = Trie::new(Rc::new(RefCell::new(store.at_version(version - 1)?)), Some(root));
+            assert_eq!(old.get(&[3, 1])?, vec![3; 40]);
+            assert_eq!(old.iter().count(), 64);
+        }
+        drop(store);
+
+        let store = Rc::new(RefCell::new(PathStore::open(&path, b"", 1)?));
+        assert_eq!(store.borrow().len(), len);
+        let mut trie = Trie::new(Rc::clone(&store), Some(root));
+        assert_eq!(trie.calculate_root()?, root_hash);
+        let report = crate::check(&mut *store.borrow_mut(), root);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        drop(trie);
+        let store = Rc::try_unwrap(store).ok().unwrap().into_inner();
+
+        // Wrappers that track offsets refuse a store that overwrites them.
+        assert!(crate::prune::PruningStore::new(store, 2).is_err());
+
+        std::fs::remove_file(format!("{}.history.{}", path, version))?;
+        std::fs::remove_file(format!("{}.journal", path))?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pins_follow_overwrites() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-pathdb-pins-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let store = Rc::new(RefCell::new(crate::pin::PinningStore::new(PathStore::new(&path, b"", 0)?, 2)));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for i in 0..3u8 {
+            for j in 0..16u8 {
+                trie.insert(&[j << 4], &[i; 40])?;
+            }
+            let result = trie.commit()?;
+            assert!(store.borrow().is_pinned(result.root_offset));
+
+            let mut trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+            assert_eq!(trie.get(&[5 << 4])?, vec![i; 40]);
+            assert_eq!(trie.calculate_root()?, result.root_hash);
+        }
+
+        std::fs::remove_file(format!("{}.journal", path))?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
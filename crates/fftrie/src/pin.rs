@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+
+use crate::node::{Node, NodeRef};
+use crate::store::{Store, SyncMode};
+use crate::view::NodeView;
+
+/// Keeps the top of the trie in memory. Every node that starts less than
+/// `depth` nibbles below the current root is pinned and served without
+/// touching the wrapped store. The pinned set follows the root: it is rebuilt
+/// whenever a new root is committed, reusing the pins of unchanged subtrees.
+/// Pins at offsets that are written again, as a store that overwrites in place
+/// does, are dropped.
+pub struct PinningStore<S: Store> {
+    store: S,
+    depth: usize,
+    root: Option<u64>,
+    pinned: HashMap<u64, Node>,
+}
+
+impl<S: Store> PinningStore<S> {
+    pub fn new(store: S, depth: usize) -> Self {
+        Self {
+            store,
+            depth,
+            root: None,
+            pinned: HashMap::new(),
+        }
+    }
+
+    /// Pins the top of the trie at `root`, replacing whatever was pinned
+    /// before. Useful for pinning a root that was loaded rather than
+    /// committed, e.g. after reopening a store.
+    pub fn pin_root(&mut self, root: u64) -> Result<(), Box<dyn Error>> {
+        let mut old = std::mem::take(&mut self.pinned);
+        let mut pinned = HashMap::new();
+
+        let mut pending = vec![(root, 0usize)];
+        while let Some((offset, depth)) = pending.pop() {
+            if depth >= self.depth || pinned.contains_key(&offset) {
+                continue;
+            }
+
+            let node = match old.remove(&offset) {
+                Some(node) => node,
+                None => self.store.get(offset)?,
+            };
+
+            match &node {
+                Node::Branch(branch) => {
+                    for offset in branch.children.iter().filter_map(NodeRef::offset) {
+                        pending.push((offset, depth + 1));
+                    }
+                }
+                Node::Extension(ext) => {
+                    if let Some(offset) = ext.child.offset() {
+                        pending.push((offset, depth + ext.path.len()));
+                    }
+                }
+                Node::Leaf(_) => {}
+            }
+
+            pinned.insert(offset, node);
+        }
+
+        self.root = Some(root);
+        self.pinned = pinned;
+        Ok(())
+    }
+
+    /// The root the pinned nodes belong to.
+    pub fn pinned_root(&self) -> Option<u64> {
+        self.root
+    }
+
+    pub fn pinned_len(&self) -> usize {
+        self.pinned.len()
+    }
+
+    /// Approximate memory taken up by the pinned nodes.
+    pub fn pinned_bytes(&self) -> usize {
+        self.pinned.values().map(|n| n.approx_size()).sum()
+    }
+
+    pub fn is_pinned(&self, offset: u64) -> bool {
+        self.pinned.contains_key(&offset)
+    }
+}
+
+impl<S: Store> Store for PinningStore<S> {
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        match self.pinned.get(&offset) {
+            Some(node) => Ok(node.clone()),
+            None => self.store.get(offset),
+        }
+    }
+
+    // Pinned nodes are already decoded, so only the rest are viewed.
+    fn view(&mut self, offset: u64) -> Result<Option<NodeView<'_>>, Box<dyn Error>> {
+        if self.pinned.contains_key(&offset) {
+            return Ok(None);
+        }
+        self.store.view(offset)
+    }
+
+    fn get_by_hash(&mut self, hash: &[u8; 32]) -> Result<Option<(u64, Node)>, Box<dyn Error>> {
+        self.store.get_by_hash(hash)
+    }
+
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+        let offset = self.store.put(node)?;
+        self.pinned.remove(&offset);
+        Ok(offset)
+    }
+
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        let offsets = self.store.put_batch(nodes)?;
+        for offset in &offsets {
+            self.pinned.remove(offset);
+        }
+        Ok(offsets)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store.flush()
+    }
+
+    fn sync_mode(&self) -> Option<SyncMode> {
+        self.store.sync_mode()
+    }
+
+    fn durable_end(&self) -> Option<u64> {
+        self.store.durable_end()
+    }
+
+    fn overwrites_in_place(&self) -> bool {
+        self.store.overwrites_in_place()
+    }
+
+    fn commit_root(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        self.store.commit_root(offset)?;
+        if self.root != Some(offset) || !self.pinned.contains_key(&offset) {
+            self.pin_root(offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::store::MemoryStore;
+    use crate::Trie;
+
+    use super::*;
+
+    // Counts the reads that make it through to the underlying store.
+    struct CountingStore {
+        store: MemoryStore,
+        reads: usize,
+    }
+
+    impl Store for CountingStore {
+        fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+            self.reads += 1;
+            self.store.get(offset)
+        }
+
+        fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+            self.store.put(node)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pins_follow_the_root() -> Result<(), Box<dyn Error>> {
+        let counting = CountingStore { store: MemoryStore::new(), reads: 0 };
+        let pinning = Rc::new(RefCell::new(PinningStore::new(counting, 2)));
+        let store: Rc<RefCell<dyn Store>> = pinning.clone();
+
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for i in 0..=255u8 {
+            trie.insert(&[i, 0], &[i; 40])?;
+        }
+        let first = trie.commit()?;
+
+        // The root branch and its 16 child branches.
+        assert_eq!(pinning.borrow().pinned_root(), Some(first.root_offset));
+        assert_eq!(pinning.borrow().pinned_len(), 17);
+
+        // Only the leaf below the pinned levels has to be read.
+        let mut trie = Trie::new(Rc::clone(&store), Some(first.root_offset));
+        pinning.borrow_mut().store.reads = 0;
+        assert_eq!(trie.get(&[0x42, 0])?, vec![0x42; 40]);
+        assert_eq!(pinning.borrow().store.reads, 1);
+
+        // Committing a new root moves the pins over, re-reading only what
+        // changed: the new root and the new branch under it. The other 15
+        // reads are the changed leaf's siblings, which hashing needs.
+        trie.insert(&[0x42, 0], b"changed")?;
+        pinning.borrow_mut().store.reads = 0;
+        let second = trie.commit()?;
+        let pinning = pinning.borrow();
+        assert_eq!(pinning.store.reads, 15 + 2);
+        assert_eq!(pinning.pinned_root(), Some(second.root_offset));
+        assert_eq!(pinning.pinned_len(), 17);
+        assert!(pinning.is_pinned(second.root_offset));
+        assert!(!pinning.is_pinned(first.root_offset));
+        assert!(pinning.pinned_bytes() > 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+use crate::node::{Node, NodeRef};
+use crate::store::{FileStore, Store, SyncMode};
+use crate::view::NodeView;
+
+/// Keeps the last `retain` committed roots alive and reference-counts every
+/// node written through it. A node's count is the number of stored parents
+/// pointing at it, plus one for each time it is a retained root. Once a root
+/// falls out of the window, nodes whose count drops to zero are queued on a
+/// free list, along with anything below them that becomes unreferenced.
+/// Freed offsets aren't reused for new writes, since a `FileStore` only
+/// appends; over one, `compact` reclaims their space once `should_compact`
+/// says it's worth it.
+///
+/// Offsets are what's counted, so a store that overwrites them in place is
+/// refused. A store opened with `open` keeps the retained roots in a file next to the
+/// store, written before the store records each commit, and rebuilds the
+/// counts from them. Nodes that were already in the underlying store when it
+/// was wrapped, and aren't below a retained root, are not tracked and are
+/// never freed.
+pub struct PruningStore<S: Store> {
+    store: S,
+    retain: usize,
+    roots: VecDeque<u64>,
+    refs: HashMap<u64, u32>,
+    freed: Vec<u64>,
+    path: Option<String>,
+}
+
+impl<S: Store> PruningStore<S> {
+    pub fn new(store: S, retain: usize) -> Result<Self, Box<dyn Error>> {
+        if retain == 0 {
+            return Err("must retain at least one root".into());
+        }
+        if store.overwrites_in_place() {
+            return Err("can't count references to offsets that are overwritten".into());
+        }
+
+        Ok(Self {
+            store,
+            retain,
+            roots: VecDeque::with_capacity(retain + 1),
+            refs: HashMap::new(),
+            freed: Vec::new(),
+            path: None,
+        })
+    }
+
+    /// Loads the retained roots from the file at `path`, if there is one, and
+    /// counts the references below them. Roots past the store's last durable
+    /// commit are dropped.
+    pub fn open(store: S, path: &str, retain: usize) -> Result<Self, Box<dyn Error>> {
+        let mut pruning = Self::new(store, retain)?;
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let end = pruning.store.durable_end().unwrap_or(u64::MAX);
+        let roots: Vec<u64> = data.chunks_exact(8)
+            .map(|root| u64::from_be_bytes(root.try_into().unwrap()))
+            .filter(|root| *root < end)
+            .collect();
+        pruning.roots = roots[roots.len().saturating_sub(retain)..].iter().copied().collect();
+        pruning.count_refs()?;
+        pruning.path = Some(path.to_string());
+        pruning.write_roots()?;
+        Ok(pruning)
+    }
+
+    /// The retained roots, oldest first.
+    pub fn roots(&self) -> impl Iterator<Item = &u64> {
+        self.roots.iter()
+    }
+
+    /// The current reference count of a tracked node.
+    pub fn ref_count(&self, offset: u64) -> Option<u32> {
+        self.refs.get(&offset).copied()
+    }
+
+    /// Offsets that are no longer referenced by any retained root.
+    pub fn freed(&self) -> &[u64] {
+        &self.freed
+    }
+
+    /// Drains the free list so the caller can reuse or reclaim those offsets.
+    pub fn take_freed(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.freed)
+    }
+
+    /// Whether the free list has grown to as many nodes as are still live.
+    pub fn should_compact(&self) -> bool {
+        !self.freed.is_empty() && self.freed.len() >= self.refs.len()
+    }
+
+    // Rebuilds the counts from the nodes the retained roots reach.
+    fn count_refs(&mut self) -> Result<(), Box<dyn Error>> {
+        self.refs.clear();
+        let mut seen = HashSet::new();
+        for root in self.roots.iter().copied() {
+            *self.refs.entry(root).or_insert(0) += 1;
+            let mut pending = vec![root];
+            while let Some(offset) = pending.pop() {
+                if !seen.insert(offset) {
+                    continue;
+                }
+                for child in children(&self.store.get(offset)?) {
+                    *self.refs.entry(child).or_insert(0) += 1;
+                    pending.push(child);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the roots file, if there is one, with the retained roots.
+    fn write_roots(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let tmp_path = format!("{}.tmp", path);
+        let mut file = File::create(&tmp_path)?;
+        for root in &self.roots {
+            file.write_all(&root.to_be_bytes())?;
+        }
+        if self.store.sync_mode() == Some(SyncMode::Fsync) {
+            file.sync_data()?;
+        }
+        std::fs::rename(&tmp_path, path)
+    }
+
+    // Counts a node just written at `offset` with `count` parents. A store
+    // can hand back an offset it already gave out, such as one holding an
+    // identical node, which adds to that node's count and takes it off the
+    // free list if it was there.
+    fn track(&mut self, offset: u64, count: u32, revived: &mut HashSet<u64>) {
+        match self.refs.get_mut(&offset) {
+            Some(refs) => *refs += count,
+            None => {
+                self.refs.insert(offset, count);
+                revived.insert(offset);
+            }
+        }
+    }
+
+    fn untrack_revived(&mut self, revived: HashSet<u64>) {
+        if !self.freed.is_empty() && !revived.is_empty() {
+            self.freed.retain(|offset| !revived.contains(offset));
+        }
+    }
+
+    fn release(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        let mut pending = vec![offset];
+        while let Some(offset) = pending.pop() {
+            let count = match self.refs.get_mut(&offset) {
+                Some(count) => count,
+                None => continue,
+            };
+
+            *count = count.saturating_sub(1);
+            if *count > 0 {
+                continue;
+            }
+
+            self.refs.remove(&offset);
+            self.freed.push(offset);
+            pending.extend(children(&self.store.get(offset)?));
+        }
+
+        Ok(())
+    }
+}
+
+impl PruningStore<FileStore> {
+    /// Rewrites the store with only the nodes the retained roots reach, which
+    /// empties the free list. Returns the new offsets of the retained roots,
+    /// oldest first; tries must be reopened at them.
+    pub fn compact(&mut self) -> Result<Vec<u64>, Box<dyn Error>> {
+        let roots: Vec<u64> = self.roots.iter().copied().collect();
+        let roots = self.store.compact(&roots)?;
+
+        // Every node is now reachable from a retained root, so the counts
+        // are rebuilt from scratch.
+        self.roots = roots.iter().copied().collect();
+        self.count_refs()?;
+        self.freed.clear();
+        self.write_roots()?;
+        Ok(roots)
+    }
+}
+
+impl<S: Store> Store for PruningStore<S> {
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        self.store.get(offset)
+    }
+
+    fn view(&mut self, offset: u64) -> Result<Option<NodeView<'_>>, Box<dyn Error>> {
+        self.store.view(offset)
+    }
+
+    fn get_by_hash(&mut self, hash: &[u8; 32]) -> Result<Option<(u64, Node)>, Box<dyn Error>> {
+        self.store.get_by_hash(hash)
+    }
+
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+        for child in children(&node) {
+            if let Some(count) = self.refs.get_mut(&child) {
+                *count += 1;
+            }
+        }
+
+        let offset = self.store.put(node)?;
+        let mut revived = HashSet::new();
+        self.track(offset, 0, &mut revived);
+        self.untrack_revived(revived);
+        Ok(offset)
+    }
+
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        // Parents within the batch are counted up front, since the nodes they
+        // point at have no offsets yet.
+        let mut counts = vec![0; nodes.len()];
+        for child in nodes.iter().flat_map(child_refs) {
+            match child {
+                NodeRef::Dirty(index) => {
+                    if let Some(count) = counts.get_mut(*index as usize) {
+                        *count += 1;
+                    }
+                }
+                NodeRef::Stored(offset) => {
+                    if let Some(count) = self.refs.get_mut(offset) {
+                        *count += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let offsets = self.store.put_batch(nodes)?;
+        let mut revived = HashSet::new();
+        for (offset, count) in offsets.iter().zip(counts) {
+            self.track(*offset, count, &mut revived);
+        }
+        self.untrack_revived(revived);
+        Ok(offsets)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store.flush()
+    }
+
+    fn sync_mode(&self) -> Option<SyncMode> {
+        self.store.sync_mode()
+    }
+
+    fn durable_end(&self) -> Option<u64> {
+        self.store.durable_end()
+    }
+
+    fn overwrites_in_place(&self) -> bool {
+        self.store.overwrites_in_place()
+    }
+
+    fn commit_root(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        if let Some(count) = self.refs.get_mut(&offset) {
+            *count += 1;
+        }
+
+        self.roots.push_back(offset);
+        while self.roots.len() > self.retain {
+            let expired = self.roots.pop_front().unwrap();
+            self.release(expired)?;
+        }
+
+        // The roots are written first, so that a commit the store records is
+        // never missing from them.
+        self.write_roots()?;
+        self.store.commit_root(offset)
+    }
+}
+
+// Inline children live inside their parent's record, so only stored ones
+// are tracked.
+fn children(node: &Node) -> Vec<u64> {
+    child_refs(node).filter_map(NodeRef::offset).collect()
+}
+
+fn child_refs(node: &Node) -> impl Iterator<Item = &NodeRef> {
+    let refs: &[NodeRef] = match node {
+        Node::Branch(branch) => &branch.children,
+        Node::Extension(ext) => std::slice::from_ref(&ext.child),
+        Node::Leaf(_) => &[],
+    };
+    refs.iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::store::MemoryStore;
+    use crate::Trie;
+
+    use super::*;
+
+    fn reachable(store: &mut dyn Store, root: u64, out: &mut HashSet<u64>) -> Result<(), Box<dyn Error>> {
+        if !out.insert(root) {
+            return Ok(());
+        }
+
+        for child in children(&store.get(root)?) {
+            reachable(store, child, out)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention_window() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(PruningStore::new(MemoryStore::new(), 2)?));
+        let dyn_store: Rc<RefCell<dyn Store>> = store.clone();
+
+        let mut roots = Vec::new();
+        let mut trie = Trie::new_empty(Rc::clone(&dyn_store));
+        for i in 0..5u8 {
+            trie.insert(&[i], &[i; 40])?;
+            trie.insert(b"shared", &[i; 40])?;
+            let result = trie.commit()?;
+            roots.push(result.root_offset);
+            trie = Trie::new(Rc::clone(&dyn_store), Some(result.root_offset));
+        }
+
+        let mut store = store.borrow_mut();
+        assert_eq!(store.roots().copied().collect::<Vec<_>>(), roots[3..]);
+
+        let mut live = HashSet::new();
+        for root in &roots[3..] {
+            reachable(&mut *store, *root, &mut live)?;
+        }
+
+        let freed: HashSet<u64> = store.freed().iter().copied().collect();
+        assert!(!freed.is_empty());
+        assert!(freed.is_disjoint(&live));
+        for root in &roots[..3] {
+            assert!(freed.contains(root));
+        }
+
+        // Unchanged leaves are shared by every version and must survive.
+        for offset in &live {
+            assert!(store.ref_count(*offset).unwrap() > 0);
+        }
+
+        assert_eq!(store.take_freed().len(), freed.len());
+        assert!(store.freed().is_empty());
+        assert!(PruningStore::new(MemoryStore::new(), 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_reclaims_freed_nodes() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-prune-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let store = Rc::new(RefCell::new(PruningStore::new(FileStore::new(&path)?, 2)?));
+        let dyn_store: Rc<RefCell<dyn Store>> = store.clone();
+
+        let mut trie = Trie::new_empty(Rc::clone(&dyn_store));
+        for i in 0..16u8 {
+            trie.insert(&[i], &[i; 40])?;
+        }
+        trie.commit()?;
+        let mut written = 0u8;
+        while !store.borrow().should_compact() {
+            trie.insert(&[written % 16], &[written; 40])?;
+            trie.commit()?;
+            written += 1;
+        }
+        drop(trie);
+
+        let size = std::fs::metadata(&path)?.len();
+        let roots = store.borrow_mut().compact()?;
+        assert_eq!(roots.len(), 2);
+        assert!(std::fs::metadata(&path)?.len() < size);
+        assert!(store.borrow().freed().is_empty());
+        assert!(!store.borrow().should_compact());
+
+        // The retained roots survive under their new offsets, and pruning
+        // carries on from the rebuilt counts.
+        for (root, i) in roots.iter().zip([written - 2, written - 1]) {
+            let trie = Trie::new(Rc::clone(&dyn_store), Some(*root));
+            assert_eq!(trie.get(&[i % 16])?, vec![i; 40]);
+        }
+        let mut trie = Trie::new(Rc::clone(&dyn_store), Some(roots[1]));
+        trie.insert(&[0], b"new")?;
+        trie.commit()?;
+        let freed: HashSet<u64> = store.borrow().freed().iter().copied().collect();
+        assert!(freed.contains(&roots[0]));
+        assert!(!freed.contains(&roots[1]));
+        assert_eq!(store.borrow().ref_count(roots[1]), Some(1));
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    // Hands back `repeat` for every write once it's set, the way a store
+    // that reuses the offset of an identical node would.
+    #[derive(Default)]
+    struct RepeatStore {
+        store: MemoryStore,
+        repeat: Option<u64>,
+    }
+
+    impl Store for RepeatStore {
+        fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+            self.store.get(offset)
+        }
+
+        fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+            match self.repeat {
+                Some(offset) => Ok(offset),
+                None => self.store.put(node),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.store.flush()
+        }
+    }
+
+    #[test]
+    fn test_repeated_offsets_add_to_counts() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(PruningStore::new(RepeatStore::default(), 1)?));
+        let dyn_store: Rc<RefCell<dyn Store>> = store.clone();
+        let mut trie = Trie::new_empty(Rc::clone(&dyn_store));
+        trie.insert(&[1], &[1; 40])?;
+        trie.insert(&[2], &[2; 40])?;
+        let first = trie.commit()?;
+        trie.insert(&[1], &[3; 40])?;
+        let second = trie.commit()?;
+        assert!(store.borrow().freed().contains(&first.root_offset));
+
+        // A batch whose nodes all land on the old root adds each of them to
+        // its count, and brings it back off the free list.
+        store.borrow_mut().store.repeat = Some(first.root_offset);
+        let mut leaf = Node::Leaf(crate::node::Leaf::new(Default::default(), vec![4; 40]));
+        leaf.set_dirty(false);
+        let mut ext = Node::Extension(crate::node::Extension::new(Default::default(), NodeRef::Dirty(0)));
+        ext.set_dirty(false);
+        store.borrow_mut().put_batch(vec![leaf.clone(), ext])?;
+        store.borrow_mut().put(leaf)?;
+        assert_eq!(store.borrow().ref_count(first.root_offset), Some(1));
+        assert!(!store.borrow().freed().contains(&first.root_offset));
+        assert_eq!(store.borrow().ref_count(second.root_offset), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_counts_survive_reopening() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("fftrie-prune-reopen-{}.db", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let roots_path = format!("{}.roots", path);
+
+        let store = Rc::new(RefCell::new(PruningStore::open(FileStore::new(&path)?, &roots_path, 2)?));
+        let dyn_store: Rc<RefCell<dyn Store>> = store.clone();
+        let mut trie = Trie::new_empty(Rc::clone(&dyn_store));
+        for i in 0..6u8 {
+            trie.insert(&[i % 3], &[i; 40])?;
+            trie.insert(b"shared", &[7; 40])?;
+            trie.commit()?;
+        }
+        drop(trie);
+        drop(dyn_store);
+        let roots: Vec<u64> = store.borrow().roots().copied().collect();
+        let counts: HashMap<u64, u32> = store.borrow().refs.clone();
+        drop(store);
+
+        let store = PruningStore::open(FileStore::open(&path)?, &roots_path, 2)?;
+        assert_eq!(store.roots().copied().collect::<Vec<_>>(), roots);
+        assert_eq!(store.refs, counts);
+        drop(store);
+
+        // A root the store lost on reopening isn't kept.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&roots_path)?;
+        file.write_all(&u64::MAX.to_be_bytes())?;
+        drop(file);
+        let store = PruningStore::open(FileStore::open(&path)?, &roots_path, 2)?;
+        assert_eq!(store.roots().copied().collect::<Vec<_>>(), roots);
+
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&roots_path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+use serde::de::{SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::node::{Node, NodeRef};
+use crate::store::Store;
+use crate::Trie;
+
+/// Encoding used for a leaf-level snapshot.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// One JSON object per line, with keys and values as 0x-prefixed hex.
+    JsonLines,
+    /// A sequence of concatenated CBOR items, with keys and values as byte strings.
+    Cbor,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Entry {
+        key: Bytes,
+        value: Bytes,
+    },
+    Trailer {
+        root_hash: Bytes,
+        count: u64,
+    },
+}
+
+/// Streams every key/value in `trie` to `writer` in key order, followed by a
+/// trailer holding the root hash and the number of entries written. Returns
+/// the number of entries.
+pub fn export<S: Store + ?Sized>(trie: &mut Trie<S>, writer: &mut dyn Write, format: Format) -> Result<u64, Box<dyn Error>> {
+    let root_hash = trie.calculate_root()?;
+
+    let mut count = 0u64;
+    for entry in trie.iter() {
+        let (key, value) = entry?;
+        write_record(writer, format, &Record::Entry {
+            key: Bytes(key),
+            value: Bytes(value),
+        })?;
+        count += 1;
+    }
+
+    write_record(writer, format, &Record::Trailer {
+        root_hash: Bytes(root_hash.to_vec()),
+        count,
+    })?;
+    writer.flush()?;
+
+    Ok(count)
+}
+
+/// Rebuilds a trie from a snapshot written by [`export`] into `store`. The
+/// rebuilt root is checked against the trailer before anything is committed.
+pub fn import<S: Store + ?Sized>(store: Rc<RefCell<S>>, reader: &mut dyn Read, format: Format) -> Result<Trie<S>, Box<dyn Error>> {
+    let mut trie = Trie::new_empty(store);
+    let mut count = 0u64;
+    let mut last_key: Option<Vec<u8>> = None;
+    let mut trailer: Option<(Vec<u8>, u64)> = None;
+
+    for record in read_records(reader, format) {
+        if trailer.is_some() {
+            return Err("snapshot has records after its trailer".into());
+        }
+
+        match record? {
+            Record::Entry { key, value } => {
+                if last_key.as_ref().is_some_and(|last| *last >= key.0) {
+                    return Err("snapshot keys are not in order".into());
+                }
+
+                trie.insert(&key.0, &value.0)?;
+                last_key = Some(key.0);
+                count += 1;
+            }
+            Record::Trailer { root_hash, count } => {
+                trailer = Some((root_hash.0, count));
+            }
+        }
+    }
+
+    let (expected_root, expected_count) = trailer.ok_or("snapshot is missing its trailer")?;
+    if expected_count != count {
+        return Err(format!("snapshot count mismatch: expected {}, got {}", expected_count, count).into());
+    }
+
+    let root_hash = trie.calculate_root()?;
+    if root_hash.as_slice() != expected_root.as_slice() {
+        return Err(format!(
+            "snapshot root mismatch: expected {}, got {}",
+            hex::encode(expected_root),
+            hex::encode(root_hash),
+        ).into());
+    }
+
+    if count > 0 {
+        trie.commit()?;
+    }
+
+    Ok(trie)
+}
+
+/// Streams every stored node reachable from `root_offset` to `writer`, one
+/// JSON object per line: the node as serialized, plus its offset. Inline
+/// children appear within their parent. Returns the number of nodes written.
+pub fn dump_nodes<S: Store + ?Sized>(store: &mut S, root_offset: u64, writer: &mut dyn Write) -> Result<u64, Box<dyn Error>> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![root_offset];
+    while let Some(offset) = pending.pop() {
+        if !seen.insert(offset) {
+            continue;
+        }
+
+        let node = store.get(offset)?;
+        let mut record = serde_json::to_value(&node)?;
+        record["offset"] = offset.into();
+        serde_json::to_writer(&mut *writer, &record)?;
+        writer.write_all(b"\n")?;
+
+        match node {
+            Node::Branch(branch) => pending.extend(branch.children.iter().rev().filter_map(NodeRef::offset)),
+            Node::Extension(ext) => pending.extend(ext.child.offset()),
+            Node::Leaf(_) => {}
+        }
+    }
+    writer.flush()?;
+
+    Ok(seen.len() as u64)
+}
+
+fn write_record(writer: &mut dyn Write, format: Format, record: &Record) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::JsonLines => {
+            serde_json::to_writer(&mut *writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+        Format::Cbor => {
+            serde_cbor::to_writer(&mut *writer, record)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_records<'a>(reader: &'a mut dyn Read, format: Format) -> Box<dyn Iterator<Item = Result<Record, Box<dyn Error>>> + 'a> {
+    match format {
+        Format::JsonLines => Box::new(
+            serde_json::Deserializer::from_reader(reader)
+                .into_iter::<Record>()
+                .map(|r| r.map_err(|e| e.into()))
+        ),
+        Format::Cbor => Box::new(
+            serde_cbor::Deserializer::from_reader(reader)
+                .into_iter::<Record>()
+                .map(|r| r.map_err(|e| e.into()))
+        ),
+    }
+}
+
+// Byte strings are written as hex in human-readable formats and as raw bytes
+// everywhere else.
+struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&format!("0x{}", hex::encode(&self.0)))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Bytes;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a byte string or a 0x-prefixed hex string")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Bytes, E> {
+                hex::decode(v.strip_prefix("0x").unwrap_or(v))
+                    .map(Bytes)
+                    .map_err(E::custom)
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+                Ok(Bytes(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                Ok(Bytes(v))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+                let mut out = Vec::new();
+                while let Some(byte) = seq.next_element()? {
+                    out.push(byte);
+                }
+                Ok(Bytes(out))
+            }
+        }
+
+        deserializer.deserialize_any(BytesVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::MemoryStore;
+
+    use super::*;
+
+    fn fixture() -> Result<Trie<MemoryStore>, Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(store);
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        trie.insert(b"dog", b"puppy")?;
+        Ok(trie)
+    }
+
+    #[test]
+    fn test_export_json_lines() -> Result<(), Box<dyn Error>> {
+        let mut trie = fixture()?;
+        let mut out = Vec::new();
+        assert_eq!(export(&mut trie, &mut out, Format::JsonLines)?, 4);
+
+        let lines: Vec<&str> = std::str::from_utf8(&out)?.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], r#"{"entry":{"key":"0x646f","value":"0x76657262"}}"#);
+        assert_eq!(lines[1], r#"{"entry":{"key":"0x646f67","value":"0x7075707079"}}"#);
+        assert_eq!(
+            lines[4],
+            r#"{"trailer":{"root_hash":"0x5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84","count":4}}"#,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<(), Box<dyn Error>> {
+        for format in [Format::JsonLines, Format::Cbor] {
+            let mut trie = fixture()?;
+            let mut out = Vec::new();
+            export(&mut trie, &mut out, format)?;
+
+            let store = Rc::new(RefCell::new(MemoryStore::new()));
+            let imported = import(store, &mut out.as_slice(), format)?;
+            assert_eq!(imported.get(b"do")?, b"verb");
+            assert_eq!(imported.get(b"horse")?, b"stallion");
+            assert_eq!(imported.get(b"doge")?, b"coin");
+            assert_eq!(imported.get(b"dog")?, b"puppy");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty() -> Result<(), Box<dyn Error>> {
+        let store: Rc<RefCell<dyn Store>> = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        let mut out = Vec::new();
+        assert_eq!(export(&mut trie, &mut out, Format::Cbor)?, 0);
+
+        let imported = import(store, &mut out.as_slice(), Format::Cbor)?;
+        assert_eq!(imported.root_offset(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_root_mismatch() -> Result<(), Box<dyn Error>> {
+        let mut trie = fixture()?;
+        let mut out = Vec::new();
+        export(&mut trie, &mut out, Format::JsonLines)?;
+
+        let tampered = std::str::from_utf8(&out)?.replace("0x7075707079", "0x7075707070");
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let err = import(store, &mut tampered.as_bytes(), Format::JsonLines).err().unwrap();
+        assert!(err.to_string().starts_with("snapshot root mismatch"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_nodes() -> Result<(), Box<dyn Error>> {
+        let mut trie = fixture()?;
+        let root = trie.commit()?.root_offset;
+        let mut out = Vec::new();
+        let count = dump_nodes(&mut *trie.store.borrow_mut(), root, &mut out)?;
+
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&out)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len() as u64, count);
+        assert_eq!(lines[0]["offset"], root);
+        assert_eq!(lines[0]["type"], "Extension");
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_missing_trailer() {
+        let data = "{\"entry\":{\"key\":\"0x646f\",\"value\":\"0x76657262\"}}\n";
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let err = import(store, &mut data.as_bytes(), Format::JsonLines).err().unwrap();
+        assert_eq!(err.to_string(), "snapshot is missing its trailer");
+    }
+}
@@ -0,0 +1,218 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::node::{Node, NodeRef};
+use crate::store::Store;
+use crate::Trie;
+
+/// How many nodes of one type a trie has, and how much space they take.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct NodeTypeStats {
+    pub count: u64,
+    /// The encoded size of the records holding these nodes, store framing
+    /// excluded. Inline nodes are part of their parent's record.
+    pub bytes: u64,
+}
+
+/// Statistics about the shape of a trie, from `Trie::stats`.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct TrieStats {
+    pub branches: NodeTypeStats,
+    pub extensions: NodeTypeStats,
+    pub leaves: NodeTypeStats,
+    /// Nodes embedded in their parent's record.
+    pub inline: u64,
+    /// Nodes in a record of their own whose encoding is short enough to be
+    /// embedded in their parent. The root doesn't count.
+    pub inline_eligible: u64,
+    /// The number of leaves at each depth, the root being at depth 0.
+    pub leaf_depths: Vec<u64>,
+    /// The children of all branches, for the average fan-out.
+    pub branch_children: u64,
+    /// Values held by leaves and branches.
+    pub values: u64,
+    pub value_bytes: u64,
+    /// The number of values by size, in power of two buckets: bucket 0 holds
+    /// empty values and bucket `i` sizes from `2^(i-1)` to `2^i - 1`.
+    pub value_sizes: Vec<u64>,
+}
+
+impl TrieStats {
+    pub fn nodes(&self) -> u64 {
+        self.branches.count + self.extensions.count + self.leaves.count
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.branches.bytes + self.extensions.bytes + self.leaves.bytes
+    }
+
+    /// The average number of children per branch.
+    pub fn average_fanout(&self) -> f64 {
+        if self.branches.count == 0 {
+            return 0.0;
+        }
+        self.branch_children as f64 / self.branches.count as f64
+    }
+
+    fn add_value(&mut self, value: &[u8]) {
+        let bucket = (usize::BITS - value.len().leading_zeros()) as usize;
+        if self.value_sizes.len() <= bucket {
+            self.value_sizes.resize(bucket + 1, 0);
+        }
+        self.value_sizes[bucket] += 1;
+        self.values += 1;
+        self.value_bytes += value.len() as u64;
+    }
+}
+
+impl fmt::Display for TrieStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "nodes: {} ({} bytes)", self.nodes(), self.bytes())?;
+        writeln!(f, "branches: {} ({} bytes), average fan-out {:.2}", self.branches.count, self.branches.bytes, self.average_fanout())?;
+        writeln!(f, "extensions: {} ({} bytes)", self.extensions.count, self.extensions.bytes)?;
+        writeln!(f, "leaves: {} ({} bytes)", self.leaves.count, self.leaves.bytes)?;
+        writeln!(f, "inline: {}, inline eligible: {}", self.inline, self.inline_eligible)?;
+        writeln!(f, "values: {} ({} bytes)", self.values, self.value_bytes)?;
+
+        writeln!(f, "leaf depths:")?;
+        for (depth, count) in self.leaf_depths.iter().enumerate().filter(|(_, count)| **count > 0) {
+            writeln!(f, "    {:>3}: {}", depth, count)?;
+        }
+        writeln!(f, "value sizes:")?;
+        for (bucket, count) in self.value_sizes.iter().enumerate().filter(|(_, count)| **count > 0) {
+            let (low, high) = match bucket {
+                0 => (0, 0),
+                _ => (1u64 << (bucket - 1), (1u64 << bucket) - 1),
+            };
+            writeln!(f, "    {:>5}-{:<5}: {}", low, high, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Store + ?Sized> Trie<S> {
+    /// Walks the whole trie, uncommitted changes included, and collects
+    /// statistics about its nodes and values. Nodes not written yet are sized
+    /// as if each was written as a record of its own.
+    pub fn stats(&mut self) -> Result<TrieStats, Box<dyn Error>> {
+        let mut stats = TrieStats::default();
+        if self.root.is_empty() {
+            return Ok(stats);
+        }
+
+        // Hashing sets the hashes of the arena nodes, which are part of their
+        // encoding.
+        self.calculate_root()?;
+        let root = self.root.clone();
+        self.visit(&root, 0, &mut stats)?;
+        Ok(stats)
+    }
+
+    fn visit(&self, node_ref: &NodeRef, depth: usize, stats: &mut TrieStats) -> Result<(), Box<dyn Error>> {
+        let node = self.get_node(node_ref)?;
+        let bytes = match node_ref {
+            NodeRef::Inline(_) => {
+                stats.inline += 1;
+                0
+            }
+            _ => {
+                if depth > 0 && node.hash().is_some_and(|hash| hash.is_embedded()) {
+                    stats.inline_eligible += 1;
+                }
+                record_size(&node)?
+            }
+        };
+
+        match &node {
+            Node::Leaf(leaf) => {
+                stats.leaves.count += 1;
+                stats.leaves.bytes += bytes;
+                if stats.leaf_depths.len() <= depth {
+                    stats.leaf_depths.resize(depth + 1, 0);
+                }
+                stats.leaf_depths[depth] += 1;
+                stats.add_value(&leaf.value);
+            }
+            Node::Extension(ext) => {
+                stats.extensions.count += 1;
+                stats.extensions.bytes += bytes;
+                self.visit(&ext.child, depth + 1, stats)?;
+            }
+            Node::Branch(branch) => {
+                stats.branches.count += 1;
+                stats.branches.bytes += bytes;
+                if let Some(value) = &branch.value {
+                    stats.add_value(value);
+                }
+                for child in branch.children.iter().filter(|child| !child.is_empty()) {
+                    stats.branch_children += 1;
+                    self.visit(child, depth + 1, stats)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The encoded size of a node's record. References to arena nodes take up as
+// much space as those to stored ones.
+fn record_size(node: &Node) -> Result<u64, Box<dyn Error>> {
+    let mut node = node.clone();
+    let children = match &mut node {
+        Node::Branch(branch) => &mut branch.children[..],
+        Node::Extension(ext) => std::slice::from_mut(&mut ext.child),
+        Node::Leaf(_) => &mut [],
+    };
+    for child in children.iter_mut().filter(|child| matches!(child, NodeRef::Dirty(_))) {
+        *child = NodeRef::Stored(0);
+    }
+    let mut record = Vec::new();
+    node.to_writer(&mut record)?;
+    Ok(record.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::store::MemoryStore;
+
+    use super::*;
+
+    #[test]
+    fn test_stats() -> Result<(), Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        assert_eq!(trie.stats()?, TrieStats::default());
+
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        trie.insert(b"dog", b"puppy")?;
+        let before = trie.stats()?;
+
+        // extension 6 -> branch { 4: extension -> branch "do" { 6: extension
+        // -> branch "dog" { 6: leaf "doge" } }, 8: leaf "horse" }
+        assert_eq!(before.leaves.count, 2);
+        assert_eq!(before.branches.count, 3);
+        assert_eq!(before.extensions.count, 3);
+        assert_eq!(before.values, 4);
+        assert_eq!(before.value_bytes, 4 + 8 + 4 + 5);
+        assert_eq!(before.value_sizes, vec![0, 0, 0, 3, 1]);
+        assert_eq!(before.leaf_depths, vec![0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(before.average_fanout(), 4.0 / 3.0);
+        assert!(before.leaves.bytes > 12);
+
+        // Committing moves the small nodes into their parents without
+        // changing the shape of the trie.
+        trie.commit()?;
+        let after = trie.stats()?;
+        assert_eq!(after.nodes(), before.nodes());
+        assert_eq!(after.leaf_depths, before.leaf_depths);
+        assert_eq!(after.inline, before.inline_eligible);
+        assert_eq!(after.inline_eligible, 0);
+        assert!(after.to_string().contains("leaves: 2"));
+        Ok(())
+    }
+}
@@ -1,19 +1,95 @@
 use std::collections::HashMap;
 use std::error::Error;
 use std::io;
-use std::io::{BufWriter, Read, Seek, Write};
+use std::io::{BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
 
 use memmap2::{Mmap, MmapOptions};
 
-use crate::node::Node;
+use crate::cache::ClockCache;
+use crate::node::{Node, NodeRef};
+use crate::root_hash;
+use crate::superblock;
+use crate::superblock::Superblock;
+use crate::view::NodeView;
 
 pub trait Store {
-    fn get(&mut self, offset: i64) -> Result<Node, Box<dyn Error>>;
-    fn put(&mut self, node: Node) -> Result<i64, Box<dyn Error>>;
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>>;
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>>;
+
+    /// Reads several nodes at once, returned in the order they were asked for.
+    fn get_many(&mut self, offsets: &[u64]) -> Result<Vec<Node>, Box<dyn Error>> {
+        offsets.iter().map(|offset| self.get(*offset)).collect()
+    }
+
+    /// Writes several nodes at once and returns their offsets. A child of
+    /// `NodeRef::Dirty(i)` points at the `i`th node of the same batch, which
+    /// the store resolves as it assigns offsets.
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        let mut offsets = Vec::with_capacity(nodes.len());
+        for mut node in nodes {
+            resolve_batch_refs(&mut node, &offsets)?;
+            offsets.push(self.put(node)?);
+        }
+        Ok(offsets)
+    }
+
+    /// Reads a node in place, without decoding it. Stores that can't hand
+    /// out views return `None`, and callers fall back to `get`.
+    fn view(&mut self, _offset: u64) -> Result<Option<NodeView<'_>>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    /// Finds a stored node by the keccak of its encoding, along with its
+    /// offset. Stores without a hash index return `None`.
+    fn get_by_hash(&mut self, _hash: &[u8; 32]) -> Result<Option<(u64, Node)>, Box<dyn Error>> {
+        Ok(None)
+    }
 
     fn flush(&mut self) -> io::Result<()>;
+
+    /// How hard the store works to make commits durable, for stores that
+    /// persist them.
+    fn sync_mode(&self) -> Option<SyncMode> {
+        None
+    }
+
+    /// The end of the last commit that survives reopening the store. Offsets
+    /// at or past it belong to writes that would be rolled back.
+    fn durable_end(&self) -> Option<u64> {
+        None
+    }
+
+    /// Whether a write can replace the node at an offset handed out before,
+    /// rather than only ever adding new ones.
+    fn overwrites_in_place(&self) -> bool {
+        false
+    }
+
+    /// Called by `Trie::commit` once a new root has been written and flushed.
+    fn commit_root(&mut self, _offset: u64) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
 }
 
+/// Replaces the batch references among a node's children with the offsets
+/// the earlier nodes of the batch were written at.
+pub fn resolve_batch_refs(node: &mut Node, offsets: &[u64]) -> Result<(), Box<dyn Error>> {
+    let resolve = |child: &mut NodeRef| -> Result<(), Box<dyn Error>> {
+        if let NodeRef::Dirty(index) = child {
+            *child = NodeRef::Stored(*offsets.get(*index as usize).ok_or("batch reference to a later node")?);
+        }
+        Ok(())
+    };
+
+    match node {
+        Node::Branch(branch) => branch.children.iter_mut().try_for_each(resolve),
+        Node::Extension(ext) => resolve(&mut ext.child),
+        Node::Leaf(_) => Ok(()),
+    }
+}
+
+#[derive(Default)]
 pub struct MemoryStore {
     nodes: Vec<Node>,
 }
@@ -27,15 +103,15 @@ impl MemoryStore {
 }
 
 impl Store for MemoryStore {
-    fn get(&mut self, offset: i64) -> Result<Node, Box<dyn Error>> {
-        self.nodes.get(offset as usize - 1)
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        self.nodes.get(offset as usize)
+            .cloned()
             .ok_or("node not found".into())
-            .map(|n| n.clone())
     }
 
-    fn put(&mut self, node: Node) -> Result<i64, Box<dyn Error>> {
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
         self.nodes.push(node);
-        Ok(self.nodes.len() as i64)
+        Ok(self.nodes.len() as u64 - 1)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -43,105 +119,1256 @@ impl Store for MemoryStore {
     }
 }
 
+// Record type appended after each committed root. It holds the root's offset
+// and hash so that the history of roots can be recovered when reopening.
+const COMMIT_MARKER: u8 = 3;
+
+// Offsets carry the segment id in their upper bits and the position within
+// the segment in the lower ones.
+const SEGMENT_BITS: u32 = 40;
+
+/// The largest segment a `FileStore` can address, and the segment size of
+/// stores created with `FileStore::new`.
+pub const MAX_SEGMENT_SIZE: u64 = 1 << SEGMENT_BITS;
+
+// The active segment's file and mapping grow ahead of the data by doubling,
+// within these bounds, so that most flushes don't have to remap.
+const MIN_GROWTH: u64 = 1 << 20;
+const MAX_GROWTH: u64 = 1 << 30;
+
+/// How hard `FileStore` works to make each commit durable.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SyncMode {
+    /// Commits are written to the file but not recorded in the superblock, so
+    /// they are lost on reopen unless `FileStore::sync` is called first.
+    None,
+    /// Commits are recorded in the superblock but nothing is fsynced. They
+    /// survive the process crashing, but not the machine.
+    Flush,
+    /// Data is fsynced before the superblock is written, and the superblock is
+    /// fsynced afterwards.
+    Fsync,
+}
+
+/// An append-only store split into segment files of at most `segment_size`
+/// bytes. Segment 0 lives at the store's path and starts with the header;
+/// later segments live next to it as `<path>.<generation>.<id>`. Only the
+/// segment being written to is kept mapped eagerly, older ones are mapped the
+/// first time they are read from.
+///
+/// The segment being written to is preallocated and mapped past its logical
+/// end, which is trimmed back when the segment is sealed or the store dropped.
 pub struct FileStore {
+    path: PathBuf,
+    segment_size: u64,
+    generation: u32,
+    sealed: Vec<Option<Mmap>>,
+    active: u32,
+    head: std::fs::File,
     file: std::fs::File,
     buf: Vec<u8>,
-    disk_size: i64,
-    mem_size: i64,
+    disk_size: u64,
+    mem_size: u64,
+    capacity: u64,
     mmap: Mmap,
+    roots: Vec<u64>,
+    superblock: Option<Superblock>,
+    sync_mode: SyncMode,
+    read_only: bool,
 }
 
 impl FileStore {
     pub fn new(path: &str) -> Result<Self, Box<dyn Error>> {
+        Self::new_segmented(path, MAX_SEGMENT_SIZE)
+    }
+
+    /// Creates an empty store that rolls over to a new segment file whenever
+    /// the current one would grow past `segment_size` bytes.
+    pub fn new_segmented(path: &str, segment_size: u64) -> Result<Self, Box<dyn Error>> {
+        Self::create(Path::new(path), segment_size, 0)
+    }
+
+    fn create(path: &Path, segment_size: u64, generation: u32) -> Result<Self, Box<dyn Error>> {
+        if segment_size <= superblock::HEADER_SIZE as u64 || segment_size > MAX_SEGMENT_SIZE {
+            return Err("invalid segment size".into());
+        }
+
         let mut file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .truncate(true)
             .open(path)?;
+        file.write_all(&superblock::new_header(generation, segment_size))?;
+        remove_stale_segments(path, generation, 0)?;
+
         let size = file.seek(io::SeekFrom::End(0))?;
         Ok(Self {
+            path: path.to_path_buf(),
+            segment_size,
+            generation,
+            sealed: Vec::new(),
+            active: 0,
+            head: file.try_clone()?,
             file: file.try_clone()?,
             buf: Vec::with_capacity(10 * 1024 * 1024),
-            disk_size: size as i64,
-            mem_size: size as i64,
+            disk_size: size,
+            mem_size: size,
+            capacity: size,
             mmap: unsafe { MmapOptions::new().len(size as usize).map(&file)? },
+            roots: Vec::new(),
+            superblock: None,
+            sync_mode: SyncMode::Fsync,
+            read_only: false,
+        })
+    }
+
+    /// Opens an existing store without discarding its contents, creating it
+    /// if it doesn't exist. The store is rolled back to the root recorded in
+    /// its superblock: anything written after that commit, such as a record
+    /// torn by a crash or a commit made under `SyncMode::None`, is truncated.
+    pub fn open(path: &str) -> Result<Self, Box<dyn Error>> {
+        Self::open_path(Path::new(path), false)
+    }
+
+    /// Opens an existing store for reading only, leaving its files exactly as
+    /// they are. The store is read as of the root recorded in its superblock,
+    /// like with `open`, but nothing written past it is truncated. Writing to
+    /// the store is an error.
+    pub fn open_read_only(path: &str) -> Result<Self, Box<dyn Error>> {
+        Self::open_path(Path::new(path), true)
+    }
+
+    fn open_path(path: &Path, read_only: bool) -> Result<Self, Box<dyn Error>> {
+        let mut head = std::fs::OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .create(!read_only)
+            .truncate(false)
+            .open(path)?;
+        if head.seek(io::SeekFrom::End(0))? == 0 {
+            if read_only {
+                return Err("store is empty".into());
+            }
+            head.write_all(&superblock::new_header(0, MAX_SEGMENT_SIZE))?;
+        }
+
+        let header = superblock::read_header(&unsafe { Mmap::map(&head)? })?;
+        let (last_segment, end) = match header.superblock {
+            Some(sb) => split_offset(sb.end),
+            None => (0, superblock::HEADER_SIZE),
+        };
+
+        let mut roots = Vec::new();
+        let mut sealed = Vec::new();
+        let mut file = head.try_clone()?;
+        let mut mmap = unsafe { MmapOptions::new().len(0).map(&head)? };
+        for id in 0..=last_segment {
+            file = if id == 0 {
+                head.try_clone()?
+            } else {
+                std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(!read_only)
+                    .open(segment_path(path, header.generation, id))?
+            };
+
+            let size = file.seek(io::SeekFrom::End(0))?;
+            let committed = if id == last_segment { end as u64 } else { size };
+            if committed > size {
+                return Err("store is shorter than its last commit".into());
+            }
+
+            if committed < size && !read_only {
+                file.set_len(committed)?;
+                file.sync_all()?;
+                file.seek(io::SeekFrom::End(0))?;
+            }
+
+            mmap = unsafe { MmapOptions::new().len(committed as usize).map(&file)? };
+            let start = if id == 0 { superblock::HEADER_SIZE } else { 0 };
+            roots.extend(scan_roots(&mmap, start));
+            if id < last_segment {
+                sealed.push(None);
+            }
+        }
+        if !read_only {
+            remove_stale_segments(path, header.generation, last_segment)?;
+        }
+
+        let size = mmap.len() as u64;
+        Ok(Self {
+            path: path.to_path_buf(),
+            segment_size: header.segment_size,
+            generation: header.generation,
+            sealed,
+            active: last_segment,
+            head,
+            file,
+            buf: Vec::with_capacity(10 * 1024 * 1024),
+            disk_size: size,
+            mem_size: size,
+            capacity: size,
+            mmap,
+            roots,
+            superblock: header.superblock,
+            sync_mode: SyncMode::Fsync,
+            read_only,
         })
     }
+
+    pub fn set_sync_mode(&mut self, mode: SyncMode) {
+        self.sync_mode = mode;
+    }
+
+    /// Every committed root in the store, oldest first.
+    pub fn roots(&self) -> &[u64] {
+        &self.roots
+    }
+
+    /// The most recently committed root, if any.
+    pub fn last_root(&self) -> Option<u64> {
+        self.roots.last().copied()
+    }
+
+    /// The offset and hash of the root recorded in the superblock, which is
+    /// the one the store will be reopened at.
+    pub fn durable_root(&self) -> Option<(u64, [u8; 32])> {
+        self.superblock.map(|sb| (sb.root, sb.root_hash))
+    }
+
+    /// The number of segment files the store currently spans.
+    pub fn segment_count(&self) -> usize {
+        self.active as usize + 1
+    }
+
+    /// Flushes and fsyncs everything written so far and records the latest
+    /// root in the superblock, whatever the sync mode.
+    pub fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.check_writable()?;
+        self.flush()?;
+        self.file.sync_data()?;
+
+        if let Some(root) = self.last_root() {
+            if self.superblock.map(|sb| sb.root) != Some(root) {
+                self.write_superblock(root)?;
+                self.head.sync_data()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_superblock(&mut self, root: u64) -> Result<(), Box<dyn Error>> {
+        let hash = self.get(root)?.hash().ok_or("root has no hash")?;
+        let sb = Superblock {
+            seq: self.superblock.map(|sb| sb.seq + 1).unwrap_or(1),
+            root,
+            root_hash: root_hash(&hash),
+            end: join_offset(self.active, self.disk_size),
+        };
+
+        self.head.seek(io::SeekFrom::Start(sb.slot_offset()))?;
+        self.head.write_all(&sb.encode())?;
+        self.head.seek(io::SeekFrom::End(0))?;
+        self.superblock = Some(sb);
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), Box<dyn Error>> {
+        if self.read_only {
+            return Err("store is opened read-only".into());
+        }
+        Ok(())
+    }
+
+    // Buffers a record, rolling over to a new segment first if it wouldn't fit
+    // in the active one. Returns the record's offset.
+    fn append(&mut self, record: &[u8]) -> Result<u64, Box<dyn Error>> {
+        self.check_writable()?;
+        let start = if self.active == 0 { superblock::HEADER_SIZE as u64 } else { 0 };
+        if self.mem_size + record.len() as u64 > self.segment_size && self.mem_size > start {
+            self.roll_over()?;
+        }
+
+        self.buf.write_all(record)?;
+        let offset = join_offset(self.active, self.mem_size);
+        self.mem_size += record.len() as u64;
+        Ok(offset)
+    }
+
+    // Seals the active segment and starts a new one. The sealed segment is
+    // always fsynced, since a later superblock will point past it.
+    fn roll_over(&mut self) -> Result<(), Box<dyn Error>> {
+        self.flush()?;
+        self.file.set_len(self.disk_size)?;
+        self.file.sync_data()?;
+        let sealed = unsafe { MmapOptions::new().len(self.disk_size as usize).map(&self.file)? };
+
+        let next = self.active + 1;
+        if next as u64 >= 1 << (63 - SEGMENT_BITS) {
+            return Err("store has run out of segments".into());
+        }
+
+        let path = segment_path(&self.path, self.generation, next);
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        if self.sync_mode != SyncMode::None {
+            sync_parent_dir(&path)?;
+        }
+
+        self.mmap = unsafe { MmapOptions::new().len(0).map(&file)? };
+        self.sealed.push(Some(sealed));
+        self.file = file;
+        self.active = next;
+        self.disk_size = 0;
+        self.mem_size = 0;
+        self.capacity = 0;
+        Ok(())
+    }
+
+    // Extends the active segment's file and mapping so that at least `needed`
+    // bytes fit.
+    fn grow(&mut self, needed: u64) -> io::Result<()> {
+        let current = self.capacity;
+        let step = current.clamp(MIN_GROWTH, MAX_GROWTH);
+        let capacity = (current + step).min(self.segment_size).max(needed);
+
+        self.file.set_len(capacity)?;
+        self.mmap = unsafe { MmapOptions::new().len(capacity as usize).map(&self.file)? };
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    fn segment_data(&mut self, segment: u32) -> Result<&[u8], Box<dyn Error>> {
+        if segment == self.active {
+            return Ok(&self.mmap[..self.disk_size as usize]);
+        }
+
+        let slot = self.sealed.get_mut(segment as usize).ok_or("offset out of bounds")?;
+        if slot.is_none() {
+            let file = std::fs::File::open(segment_path(&self.path, self.generation, segment))?;
+            *slot = Some(unsafe { Mmap::map(&file)? });
+        }
+
+        Ok(slot.as_ref().unwrap())
+    }
+
+    /// Rewrites the store so that it only holds the nodes reachable from
+    /// `roots`, then atomically replaces the original files with the result.
+    /// Returns the new offset of each root, in the same order. The last root
+    /// becomes the durable one.
+    ///
+    /// Every offset handed out before compaction is invalid afterwards, so
+    /// tries must be reopened at the returned roots.
+    pub fn compact(&mut self, roots: &[u64]) -> Result<Vec<u64>, Box<dyn Error>> {
+        self.check_writable()?;
+        self.flush()?;
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let generation = self.generation + 1;
+        let mut out = FileStore::create(&tmp_path, self.segment_size, generation)?;
+        let mut remapped = HashMap::new();
+        let mut new_roots = Vec::with_capacity(roots.len());
+        for root in roots {
+            let new_root = self.copy_node(*root, &mut out, &mut remapped)?;
+            out.flush()?;
+            out.commit_root(new_root)?;
+            new_roots.push(new_root);
+        }
+        out.sync()?;
+        let last_segment = out.active;
+        drop(out);
+
+        // The new generation's segments can't clash with the current ones, so
+        // they can be moved into place first. Replacing segment 0, which holds
+        // the header, is what makes the new generation live.
+        for id in 1..=last_segment {
+            std::fs::rename(segment_path(&tmp_path, generation, id), segment_path(&self.path, generation, id))?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        sync_parent_dir(&self.path)?;
+
+        let sync_mode = self.sync_mode;
+        *self = FileStore::open_path(&self.path, false)?;
+        self.sync_mode = sync_mode;
+
+        Ok(new_roots)
+    }
+
+    // Copies the subtree at `offset` into `out`, children first so that their
+    // new offsets are known by the time the parent is written.
+    fn copy_node(
+        &mut self,
+        offset: u64,
+        out: &mut FileStore,
+        remapped: &mut HashMap<u64, u64>,
+    ) -> Result<u64, Box<dyn Error>> {
+        if let Some(new_offset) = remapped.get(&offset) {
+            return Ok(*new_offset);
+        }
+
+        let mut node = self.get(offset)?;
+        match &mut node {
+            Node::Branch(branch) => {
+                for child in branch.children.iter_mut() {
+                    if let NodeRef::Stored(offset) = child {
+                        *offset = self.copy_node(*offset, out, remapped)?;
+                    }
+                }
+            }
+            Node::Extension(ext) => {
+                if let NodeRef::Stored(offset) = &mut ext.child {
+                    *offset = self.copy_node(*offset, out, remapped)?;
+                }
+            }
+            Node::Leaf(_) => {}
+        }
+
+        let new_offset = out.put(node)?;
+        remapped.insert(offset, new_offset);
+        Ok(new_offset)
+    }
 }
 
 impl Store for FileStore {
-    fn get(&mut self, offset: i64) -> Result<Node, Box<dyn Error>> {
-        if offset > self.mem_size {
-            return Err("offset out of bounds".into());
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        let (segment, local) = split_offset(offset);
+
+        // Records that haven't been flushed yet are only in the write buffer.
+        if segment == self.active && local as u64 >= self.disk_size {
+            return decode_record(&self.buf, local - self.disk_size as usize);
         }
 
-        let sizer: &[u8; 2] = &self.mmap[offset as usize..offset as usize + 2].try_into()?;
-        let size = u16::from_be_bytes(*sizer) as usize;
-        let data = &self.mmap[offset as usize + 2..offset as usize + 2 + size];
-        let node = Node::from_slice(data)?;
-        Ok(node)
+        let data = self.segment_data(segment)?;
+        decode_record(data, local)
     }
 
-    fn put(&mut self, node: Node) -> Result<i64, Box<dyn Error>> {
-        let mut buf = Vec::new();
-        node.to_writer(&mut buf)?;
-        self.buf.write_all(&(u16::to_be_bytes(buf.len() as u16) as [u8; 2]))?;
-        self.buf.write_all(&buf)?;
-        let offset = self.mem_size;
-        self.mem_size += buf.len() as i64 + 2;
-        Ok(offset)
+    fn view(&mut self, offset: u64) -> Result<Option<NodeView<'_>>, Box<dyn Error>> {
+        let (segment, local) = split_offset(offset);
+        let record = if segment == self.active && local as u64 >= self.disk_size {
+            record_at(&self.buf, local - self.disk_size as usize)?
+        } else {
+            record_at(self.segment_data(segment)?, local)?
+        };
+        Ok(Some(NodeView::new(record)?))
+    }
+
+    // Reads in offset order, so a batch walks each segment front to back.
+    fn get_many(&mut self, offsets: &[u64]) -> Result<Vec<Node>, Box<dyn Error>> {
+        let mut order: Vec<usize> = (0..offsets.len()).collect();
+        order.sort_unstable_by_key(|i| offsets[*i]);
+
+        let mut nodes = vec![None; offsets.len()];
+        for i in order {
+            nodes[i] = Some(self.get(offsets[i])?);
+        }
+        Ok(nodes.into_iter().map(Option::unwrap).collect())
+    }
+
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
+        let mut record = Vec::new();
+        encode_record(&node, &mut record)?;
+        self.append(&record)
+    }
+
+    // Encodes the whole batch through a single scratch buffer.
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        let mut offsets = Vec::with_capacity(nodes.len());
+        let mut record = Vec::new();
+        for mut node in nodes {
+            resolve_batch_refs(&mut node, &offsets)?;
+            record.clear();
+            encode_record(&node, &mut record)?;
+            offsets.push(self.append(&record)?);
+        }
+        Ok(offsets)
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let end = self.disk_size + self.buf.len() as u64;
+        if end > self.capacity {
+            self.grow(end)?;
+        }
+
+        self.file.seek(io::SeekFrom::Start(self.disk_size))?;
         let bw = &mut BufWriter::new(&self.file);
         bw.write_all(&self.buf)?;
         bw.flush()?;
-        self.disk_size += self.buf.len() as i64;
+        self.disk_size = end;
         self.buf.clear();
 
-        let mmap = unsafe {
-            MmapOptions::new().len(self.disk_size as usize).map(&self.file)?
-        };
-        _ = std::mem::replace(&mut self.mmap, mmap);
+        Ok(())
+    }
+
+    fn sync_mode(&self) -> Option<SyncMode> {
+        Some(self.sync_mode)
+    }
+
+    fn durable_end(&self) -> Option<u64> {
+        Some(self.superblock.map_or(superblock::HEADER_SIZE as u64, |sb| sb.end))
+    }
+
+    fn commit_root(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        let hash = self.get(offset)?.hash().ok_or("root has no hash")?;
+        self.append(&encode_commit_marker(offset, &hash))?;
+        self.flush()?;
+        self.roots.push(offset);
+
+        match self.sync_mode {
+            SyncMode::None => {}
+            SyncMode::Flush => self.write_superblock(offset)?,
+            SyncMode::Fsync => {
+                self.file.sync_data()?;
+                self.write_superblock(offset)?;
+                self.head.sync_data()?;
+            }
+        }
 
         Ok(())
     }
 }
 
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        // Give back the space preallocated past the logical end.
+        if !self.read_only {
+            _ = self.file.set_len(self.disk_size);
+        }
+    }
+}
+
+fn split_offset(offset: u64) -> (u32, usize) {
+    ((offset >> SEGMENT_BITS) as u32, (offset & ((1 << SEGMENT_BITS) - 1)) as usize)
+}
+
+fn join_offset(segment: u32, local: u64) -> u64 {
+    ((segment as u64) << SEGMENT_BITS) | local
+}
+
+fn segment_path(path: &Path, generation: u32, id: u32) -> PathBuf {
+    if id == 0 {
+        return path.to_path_buf();
+    }
+
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}.{}", generation, id));
+    PathBuf::from(name)
+}
+
+// Deletes segment files that don't belong to the store as it stands: ones
+// left over from before a compaction, or written after the last commit.
+fn remove_stale_segments(path: &Path, generation: u32, last_segment: u32) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(()),
+    };
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let mut parts = match name.strip_prefix(&prefix) {
+            Some(rest) => rest.split('.'),
+            None => continue,
+        };
+
+        let segment = (
+            parts.next().and_then(|g| g.parse::<u32>().ok()),
+            parts.next().and_then(|id| id.parse::<u32>().ok()),
+            parts.next(),
+        );
+        if let (Some(g), Some(id), None) = segment {
+            if g != generation || id == 0 || id > last_segment {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Records are the node's encoding prefixed with its length as a big-endian u16.
+fn encode_record(node: &Node, out: &mut Vec<u8>) -> Result<(), Box<dyn Error>> {
+    let start = out.len();
+    out.extend_from_slice(&[0u8; 2]);
+    node.to_writer(&mut *out)?;
+    let len = out.len() - start - 2;
+    out[start..start + 2].copy_from_slice(&(len as u16).to_be_bytes());
+    Ok(())
+}
+
+fn decode_record(data: &[u8], pos: usize) -> Result<Node, Box<dyn Error>> {
+    Node::from_slice(record_at(data, pos)?)
+}
+
+// The encoded node of the record starting at `pos`.
+fn record_at(data: &[u8], pos: usize) -> Result<&[u8], Box<dyn Error>> {
+    if pos + 2 > data.len() {
+        return Err("offset out of bounds".into());
+    }
+
+    let size = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
+    if pos + 2 + size > data.len() {
+        return Err("offset out of bounds".into());
+    }
+
+    Ok(&data[pos + 2..pos + 2 + size])
+}
+
+fn encode_commit_marker(root: u64, hash: &[u8]) -> Vec<u8> {
+    let len = 1 + 8 + hash.len();
+    let mut buf = Vec::with_capacity(2 + len);
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+    buf.push(COMMIT_MARKER);
+    buf.extend_from_slice(&root.to_be_bytes());
+    buf.extend_from_slice(hash);
+    buf
+}
+
+// Walks the records of one segment from `start` and returns every committed
+// root found in it.
+fn scan_roots(data: &[u8], start: usize) -> Vec<u64> {
+    let mut roots = Vec::new();
+    let mut pos = start;
+
+    while pos + 2 <= data.len() {
+        let len = u16::from_be_bytes([data[pos], data[pos + 1]]) as usize;
+        let end = pos + 2 + len;
+        if len == 0 || end > data.len() {
+            break;
+        }
+
+        if data[pos + 2] == COMMIT_MARKER && len >= 9 {
+            roots.push(u64::from_be_bytes(data[pos + 3..pos + 11].try_into().unwrap()));
+        }
+
+        pos = end;
+    }
+
+    roots
+}
+
+// Makes a rename durable by fsyncing the directory that holds the file.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::File::open(dir)?.sync_all(),
+        _ => std::fs::File::open(".")?.sync_all(),
+    }
+}
+
+/// The default memory budget of a `CachingStore`.
+pub const DEFAULT_CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Counters describing how a `CachingStore` has been doing.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Reads served in place by the wrapped store's views, which bypass the
+    /// cache.
+    pub views: u64,
+    pub evictions: u64,
+    /// Nodes currently cached.
+    pub entries: usize,
+    /// Approximate memory taken up by the cached nodes.
+    pub bytes: usize,
+}
+
+/// Caches nodes read from or written to the wrapped store, keeping their
+/// approximate size within a memory budget by evicting with CLOCK. Nodes
+/// written in a batch are cached when they're first read back, rather than
+/// copied up front. Cached nodes are served from the cache even when a view is
+/// asked for; the rest are viewed in place in the wrapped store, without being
+/// cached.
 pub struct CachingStore<S: Store> {
     store: S,
-    cache: HashMap<i64, Node>,
+    cache: ClockCache<Node>,
+    stats: CacheStats,
 }
 
 impl<S: Store> CachingStore<S> {
     pub fn new(store: S) -> Self {
+        Self::with_budget(store, DEFAULT_CACHE_BYTES)
+    }
+
+    pub fn with_budget(store: S, budget: usize) -> Self {
         Self {
             store,
-            cache: HashMap::new(),
+            cache: ClockCache::new(budget),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.cache.len(),
+            bytes: self.cache.used(),
+            ..self.stats
         }
     }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    fn cache(&mut self, offset: u64, node: Node) {
+        let size = node.approx_size();
+        self.stats.evictions += self.cache.insert(offset, node, size) as u64;
+    }
 }
 
 impl<S: Store> Store for CachingStore<S> {
-    fn get(&mut self, offset: i64) -> Result<Node, Box<dyn Error>> {
-        match self.cache.get(&offset) {
-            Some(node) => Ok(node.clone()),
-            None => {
-                let node = self.store.get(offset)?;
-                self.cache.insert(offset, node.clone());
-                Ok(node)
+    fn get(&mut self, offset: u64) -> Result<Node, Box<dyn Error>> {
+        if let Some(node) = self.cache.get(offset) {
+            self.stats.hits += 1;
+            return Ok(node.clone());
+        }
+
+        self.stats.misses += 1;
+        let node = self.store.get(offset)?;
+        self.cache(offset, node.clone());
+        Ok(node)
+    }
+
+    // Serves what it can from the cache and fetches the rest in one batch.
+    fn get_many(&mut self, offsets: &[u64]) -> Result<Vec<Node>, Box<dyn Error>> {
+        let mut nodes = Vec::with_capacity(offsets.len());
+        let mut missing = Vec::new();
+        for (i, offset) in offsets.iter().enumerate() {
+            match self.cache.get(*offset) {
+                Some(node) => nodes.push(Some(node.clone())),
+                None => {
+                    nodes.push(None);
+                    missing.push(i);
+                }
+            }
+        }
+
+        self.stats.hits += (offsets.len() - missing.len()) as u64;
+        self.stats.misses += missing.len() as u64;
+        if !missing.is_empty() {
+            let wanted: Vec<u64> = missing.iter().map(|i| offsets[*i]).collect();
+            let fetched = self.store.get_many(&wanted)?;
+            for (i, node) in missing.into_iter().zip(fetched) {
+                self.cache(offsets[i], node.clone());
+                nodes[i] = Some(node);
             }
         }
+
+        Ok(nodes.into_iter().map(Option::unwrap).collect())
+    }
+
+    // A cached node has no record to view, so the caller falls back to `get`,
+    // which counts the hit.
+    fn view(&mut self, offset: u64) -> Result<Option<NodeView<'_>>, Box<dyn Error>> {
+        if self.cache.get(offset).is_some() {
+            return Ok(None);
+        }
+
+        let view = self.store.view(offset)?;
+        if view.is_some() {
+            self.stats.views += 1;
+        }
+        Ok(view)
+    }
+
+    fn get_by_hash(&mut self, hash: &[u8; 32]) -> Result<Option<(u64, Node)>, Box<dyn Error>> {
+        self.store.get_by_hash(hash)
     }
 
-    fn put(&mut self, node: Node) -> Result<i64, Box<dyn Error>> {
+    fn put(&mut self, node: Node) -> Result<u64, Box<dyn Error>> {
         let offset = self.store.put(node.clone())?;
-        self.cache.insert(offset, node);
+        self.cache(offset, node);
         Ok(offset)
     }
 
+    // Stores that overwrite in place can hand out offsets that are already
+    // cached, so those entries are dropped.
+    fn put_batch(&mut self, nodes: Vec<Node>) -> Result<Vec<u64>, Box<dyn Error>> {
+        let offsets = self.store.put_batch(nodes)?;
+        for offset in &offsets {
+            self.cache.remove(*offset);
+        }
+        Ok(offsets)
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         self.store.flush()
     }
-}
\ No newline at end of file
+
+    fn sync_mode(&self) -> Option<SyncMode> {
+        self.store.sync_mode()
+    }
+
+    fn durable_end(&self) -> Option<u64> {
+        self.store.durable_end()
+    }
+
+    fn overwrites_in_place(&self) -> bool {
+        self.store.overwrites_in_place()
+    }
+
+    fn commit_root(&mut self, offset: u64) -> Result<(), Box<dyn Error>> {
+        self.store.commit_root(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::nibbles::Nibbles;
+    use crate::node::{Branch, Extension, Leaf, NodeHash};
+    use crate::Trie;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("fftrie-{}-{}.db", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn remove_store(path: &str) -> io::Result<()> {
+        remove_stale_segments(Path::new(path), u32::MAX, 0)?;
+        std::fs::remove_file(path)
+    }
+
+    #[test]
+    fn test_file_store_roundtrip() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("roundtrip");
+        let store: Rc<RefCell<dyn Store>> = Rc::new(RefCell::new(FileStore::new(&path)?));
+
+        let mut trie1 = Trie::new_empty(Rc::clone(&store));
+        trie1.insert(b"do", b"verb")?;
+        trie1.insert(b"horse", b"stallion")?;
+        trie1.insert(b"doge", b"coin")?;
+        trie1.insert(b"dog", b"puppy")?;
+        let result = trie1.commit()?;
+
+        let mut trie2 = Trie::new(Rc::clone(&store), Some(result.root_offset));
+        assert_eq!(trie2.get(b"do")?, b"verb");
+        assert_eq!(trie2.get(b"horse")?, b"stallion");
+        assert_eq!(trie2.get(b"doge")?, b"coin");
+        assert_eq!(trie2.get(b"dog")?, b"puppy");
+        assert_eq!(trie2.calculate_root()?, result.root_hash);
+
+        // Lookups read the mapped records in place.
+        assert!(store.borrow_mut().view(result.root_offset)?.is_some());
+        assert!(trie2.get_with(b"doge", |value| value == b"coin")?);
+        assert!(trie2.get_with(b"cat", |_| ()).is_err());
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store_reopen() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("reopen");
+        let mut roots = Vec::new();
+        {
+            let store: Rc<RefCell<dyn Store>> = Rc::new(RefCell::new(FileStore::new(&path)?));
+            let mut trie = Trie::new_empty(Rc::clone(&store));
+            for i in 0..3u8 {
+                trie.insert(&[i], &[i; 40])?;
+                let result = trie.commit()?;
+                roots.push(result);
+                trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+            }
+
+            // Nodes written and flushed without a commit marker must not survive.
+            trie.insert(&[3], &[3; 40])?;
+            trie.calculate_root()?;
+            let root = trie.get_node(&trie.root)?;
+            trie.write_node(&mut root.clone(), &mut Vec::new())?;
+            store.borrow_mut().flush()?;
+        }
+
+        let uncommitted_size = std::fs::metadata(&path)?.len();
+        let committed_size = FileStore::open(&path)?.disk_size;
+        assert!(committed_size < uncommitted_size);
+
+        // Simulate a crash in the middle of a record.
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[0x00, 0x40, 0x01, 0x02])?;
+        drop(file);
+
+        let store = FileStore::open(&path)?;
+        assert_eq!(std::fs::metadata(&path)?.len(), committed_size);
+        assert_eq!(store.roots(), roots.iter().map(|r| r.root_offset).collect::<Vec<_>>());
+
+        let store: Rc<RefCell<dyn Store>> = Rc::new(RefCell::new(store));
+        let mut trie = Trie::new(Rc::clone(&store), Some(roots[2].root_offset));
+        assert_eq!(trie.calculate_root()?, roots[2].root_hash);
+        assert_eq!(trie.get(&[2])?, vec![2; 40]);
+        assert!(trie.get(&[3]).is_err());
+
+        trie.insert(&[3], &[3; 40])?;
+        let result = trie.commit()?;
+        let trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+        assert_eq!(trie.get(&[3])?, vec![3; 40]);
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_your_writes() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("read-your-writes");
+        let mut store = FileStore::new(&path)?;
+
+        let mut leaf = Node::Leaf(Leaf::new(Nibbles::from_bytes(b"dog"), b"puppy".to_vec()));
+        leaf.set_hash(NodeHash::from([0xab; 32]));
+        let first = store.put(leaf.clone())?;
+        let second = store.put(leaf)?;
+        assert!(second > first);
+
+        for offset in [first, second] {
+            match store.get(offset)? {
+                Node::Leaf(leaf) => assert_eq!(leaf.value, b"puppy"),
+                _ => panic!("expected a leaf"),
+            }
+        }
+        assert!(store.get(store.mem_size).is_err());
+        assert_eq!(store.view(second)?.unwrap().value(), Some(&b"puppy"[..]));
+
+        store.flush()?;
+        assert_eq!(store.get(second)?.hash(), Some(NodeHash::from([0xab; 32])));
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("batches");
+        let mut store = CachingStore::new(FileStore::new(&path)?);
+
+        let mut leaf = Node::Leaf(Leaf::new(Nibbles::from_bytes(b"dog"), b"puppy".to_vec()));
+        leaf.set_hash(NodeHash::from([0xab; 32]));
+        let mut branch = Node::Branch(Branch::new());
+        if let Node::Branch(b) = &mut branch {
+            b.children[3] = NodeRef::Dirty(0);
+            b.children[7] = NodeRef::Dirty(1);
+        }
+        branch.set_hash(NodeHash::from([0xcd; 32]));
+
+        let offsets = store.put_batch(vec![leaf.clone(), leaf, branch])?;
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+        assert!(store.put_batch(vec![Node::Extension(Extension::new(Nibbles::from_bytes(b"d"), NodeRef::Dirty(0)))]).is_err());
+
+        // Batch references come back resolved from the file, and then from
+        // the cache.
+        store.flush()?;
+        let wanted = [offsets[2], offsets[0], offsets[2]];
+        for nodes in [store.get_many(&wanted)?, store.get_many(&wanted)?] {
+            match &nodes[0] {
+                Node::Branch(b) => assert_eq!((b.children[3].offset(), b.children[7].offset()), (Some(offsets[0]), Some(offsets[1]))),
+                _ => panic!("expected a branch"),
+            }
+            assert_eq!(nodes[1].hash(), Some(NodeHash::from([0xab; 32])));
+            assert_eq!(nodes[2].hash(), Some(NodeHash::from([0xcd; 32])));
+        }
+        assert_eq!((store.stats().misses, store.stats().hits), (3, 3));
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_mmap_grows_in_chunks() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("grow");
+        let mut store = FileStore::new(&path)?;
+
+        let mut leaf = Node::Leaf(Leaf::new(Nibbles::from_bytes(b"dog"), vec![0x42; 1000]));
+        leaf.set_hash(NodeHash::from([0xab; 32]));
+        store.put(leaf.clone())?;
+        store.flush()?;
+        let capacity = store.capacity;
+        let mapping = store.mmap.as_ptr();
+        assert!(capacity >= MIN_GROWTH);
+
+        // Flushes that fit in the preallocated space reuse the mapping.
+        let mut offsets = Vec::new();
+        while store.disk_size + 2000 < capacity {
+            offsets.push(store.put(leaf.clone())?);
+            store.flush()?;
+        }
+        assert_eq!(store.capacity, capacity);
+        assert_eq!(store.mmap.as_ptr(), mapping);
+        assert_eq!(std::fs::metadata(&path)?.len(), capacity);
+
+        offsets.push(store.put(leaf.clone())?);
+        offsets.push(store.put(leaf.clone())?);
+        store.flush()?;
+        assert!(store.capacity >= 2 * (capacity - superblock::HEADER_SIZE as u64));
+        for offset in offsets {
+            assert_eq!(store.get(offset)?.hash(), Some(NodeHash::from([0xab; 32])));
+        }
+
+        // Dropping the store trims the file back to its logical size.
+        let disk_size = store.disk_size;
+        drop(store);
+        assert_eq!(std::fs::metadata(&path)?.len(), disk_size);
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_mode_none_needs_sync() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("sync-none");
+        let (first, second) = {
+            let file_store = Rc::new(RefCell::new(FileStore::new(&path)?));
+            let store: Rc<RefCell<dyn Store>> = file_store.clone();
+            let mut trie = Trie::new_empty(Rc::clone(&store));
+            trie.insert(b"do", b"verb")?;
+            let first = trie.commit()?;
+
+            file_store.borrow_mut().set_sync_mode(SyncMode::None);
+            let mut trie = Trie::new(Rc::clone(&store), Some(first.root_offset));
+            trie.insert(b"dog", b"puppy")?;
+            let second = trie.commit()?;
+            assert_eq!(file_store.borrow().durable_root(), Some((first.root_offset, first.root_hash)));
+            (first, second)
+        };
+
+        // The second commit was never synced, so reopening rolls it back.
+        let mut store = FileStore::open(&path)?;
+        assert_eq!(store.roots(), [first.root_offset]);
+        assert_eq!(store.durable_root(), Some((first.root_offset, first.root_hash)));
+
+        store.set_sync_mode(SyncMode::None);
+        let store = Rc::new(RefCell::new(store));
+        let dyn_store: Rc<RefCell<dyn Store>> = store.clone();
+        let mut trie = Trie::new(Rc::clone(&dyn_store), Some(first.root_offset));
+        trie.insert(b"dog", b"puppy")?;
+        let result = trie.commit()?;
+        assert_eq!(result.root_hash, second.root_hash);
+        store.borrow_mut().sync()?;
+        drop(trie);
+        drop(dyn_store);
+        drop(store);
+
+        let store = FileStore::open(&path)?;
+        assert_eq!(store.durable_root(), Some((result.root_offset, result.root_hash)));
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_read_only() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("read-only");
+        assert!(FileStore::open_read_only(&path).is_err());
+        let first = {
+            let file_store = Rc::new(RefCell::new(FileStore::new(&path)?));
+            let store: Rc<RefCell<dyn Store>> = file_store.clone();
+            let mut trie = Trie::new_empty(Rc::clone(&store));
+            trie.insert(b"do", b"verb")?;
+            let first = trie.commit()?;
+
+            file_store.borrow_mut().set_sync_mode(SyncMode::None);
+            trie.insert(b"dog", b"puppy")?;
+            trie.commit()?;
+            first
+        };
+        let len = std::fs::metadata(&path)?.len();
+
+        // Reading only sees the durable root, but leaves the unsynced commit
+        // after it in place.
+        let mut store = FileStore::open_read_only(&path)?;
+        assert_eq!(store.roots(), [first.root_offset]);
+        let root = store.get(first.root_offset)?;
+        assert_eq!(root.hash().map(|hash| root_hash(&hash)), Some(first.root_hash));
+        assert!(store.put(root).is_err());
+        assert!(store.compact(&[first.root_offset]).is_err());
+        drop(store);
+        assert_eq!(std::fs::metadata(&path)?.len(), len);
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_segmented() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("segmented");
+        let file_store = Rc::new(RefCell::new(FileStore::new_segmented(&path, 8192)?));
+        let store: Rc<RefCell<dyn Store>> = file_store.clone();
+
+        let mut roots = Vec::new();
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for i in 0..20u8 {
+            for j in 0..5u8 {
+                trie.insert(&[i, j], &[j; 64])?;
+            }
+            let result = trie.commit()?;
+            roots.push(result);
+            trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+        }
+
+        let segments = file_store.borrow().segment_count();
+        assert!(segments > 2);
+        assert!(std::path::Path::new(&format!("{}.0.1", path)).exists());
+        assert!(std::fs::metadata(&path)?.len() <= 8192);
+
+        let last = roots.last().unwrap();
+        for i in 0..20u8 {
+            assert_eq!(trie.get(&[i, 4])?, vec![4; 64]);
+        }
+        drop(trie);
+        drop(store);
+        drop(file_store);
+
+        // Older segments are only mapped once something in them is read.
+        let store = FileStore::open(&path)?;
+        assert_eq!(store.segment_count(), segments);
+        assert!(store.sealed.iter().all(|s| s.is_none()));
+        assert_eq!(store.roots(), roots.iter().map(|r| r.root_offset).collect::<Vec<_>>());
+
+        let file_store = Rc::new(RefCell::new(store));
+        let store: Rc<RefCell<dyn Store>> = file_store.clone();
+        let mut trie = Trie::new(Rc::clone(&store), Some(last.root_offset));
+        assert_eq!(trie.calculate_root()?, last.root_hash);
+        assert_eq!(trie.get(&[0, 0])?, vec![0; 64]);
+        assert!(file_store.borrow().sealed[0].is_some());
+
+        let new_roots = file_store.borrow_mut().compact(&[last.root_offset])?;
+        assert!(!std::path::Path::new(&format!("{}.0.1", path)).exists());
+        let mut trie = Trie::new(Rc::clone(&store), Some(new_roots[0]));
+        assert_eq!(trie.calculate_root()?, last.root_hash);
+        for i in 0..20u8 {
+            assert_eq!(trie.get(&[i, 4])?, vec![4; 64]);
+        }
+
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_caching_store_budget() -> Result<(), Box<dyn Error>> {
+        let caching_store = Rc::new(RefCell::new(CachingStore::with_budget(MemoryStore::new(), 2048)));
+        let store: Rc<RefCell<dyn Store>> = caching_store.clone();
+
+        let mut trie = Trie::new_empty(Rc::clone(&store));
+        for i in 0..50u8 {
+            trie.insert(&[i], &[i; 40])?;
+        }
+        let result = trie.commit()?;
+
+        // The commit's batch isn't cached until it's read.
+        let stats = caching_store.borrow().stats();
+        assert_eq!(stats.entries, 0);
+        assert_eq!(stats.hits + stats.misses, 0);
+
+        let trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+        for _ in 0..2 {
+            assert_eq!(trie.get(&[7])?, vec![7; 40]);
+        }
+
+        // The lookup goes root -> branch -> leaf, all cached by the first
+        // lookup and hit by the second.
+        let stats = caching_store.borrow().stats();
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.hits, 3);
+
+        for i in 0..50u8 {
+            assert_eq!(trie.get(&[i])?, vec![i; 40]);
+        }
+        let stats = caching_store.borrow().stats();
+        assert!(stats.bytes <= 2048);
+        assert!(stats.evictions > 0);
+
+        // Uncached nodes are viewed in place in a store that has views, and
+        // cached ones are served from the cache.
+        let path = temp_path("caching-views");
+        let caching_store = Rc::new(RefCell::new(CachingStore::new(FileStore::new(&path)?)));
+        let mut trie = Trie::new_empty(Rc::clone(&caching_store));
+        trie.insert(b"do", b"verb")?;
+        let offset = trie.commit()?.root_offset;
+        assert!(caching_store.borrow_mut().view(offset)?.is_some());
+        assert_eq!(trie.get(b"do")?, b"verb");
+        let stats = caching_store.borrow().stats();
+        assert_eq!((stats.views, stats.hits, stats.misses), (2, 0, 0));
+
+        caching_store.borrow_mut().get(offset)?;
+        assert!(caching_store.borrow_mut().view(offset)?.is_none());
+        assert_eq!(trie.get(b"do")?, b"verb");
+        let stats = caching_store.borrow().stats();
+        assert_eq!((stats.views, stats.hits, stats.misses), (2, 1, 1));
+        drop(trie);
+        drop(caching_store);
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_rejects_foreign_files() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("foreign");
+        std::fs::write(&path, b"definitely not a trie")?;
+        assert!(FileStore::open(&path).is_err());
+        remove_store(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<(), Box<dyn Error>> {
+        let path = temp_path("compact");
+        let store = Rc::new(RefCell::new(FileStore::new(&path)?));
+        let dyn_store: Rc<RefCell<dyn Store>> = store.clone();
+
+        let mut roots = Vec::new();
+        let mut trie = Trie::new_empty(Rc::clone(&dyn_store));
+        for i in 0..10u8 {
+            trie.insert(&[i], &[i; 40])?;
+            trie.insert(&[i, i], b"value")?;
+            let result = trie.commit()?;
+            roots.push(result);
+            trie = Trie::new(Rc::clone(&dyn_store), Some(result.root_offset));
+        }
+
+        let size_before = std::fs::metadata(&path)?.len();
+        let kept = [roots[4].root_offset, roots[9].root_offset];
+        let new_roots = store.borrow_mut().compact(&kept)?;
+        assert!(std::fs::metadata(&path)?.len() < size_before);
+        assert_eq!(FileStore::open(&path)?.roots(), new_roots);
+
+        let mut old = Trie::new(Rc::clone(&dyn_store), Some(new_roots[0]));
+        assert_eq!(old.calculate_root()?, roots[4].root_hash);
+        assert_eq!(old.get(&[4])?, vec![4; 40]);
+        assert!(old.get(&[5]).is_err());
+
+        let mut latest = Trie::new(Rc::clone(&dyn_store), Some(new_roots[1]));
+        assert_eq!(latest.calculate_root()?, roots[9].root_hash);
+        for i in 0..10u8 {
+            assert_eq!(latest.get(&[i])?, vec![i; 40]);
+            assert_eq!(latest.get(&[i, i])?, b"value");
+        }
+
+        // The compacted store keeps accepting writes.
+        latest.insert(b"new", b"key")?;
+        let result = latest.commit()?;
+        let trie = Trie::new(Rc::clone(&dyn_store), Some(result.root_offset));
+        assert_eq!(trie.get(b"new")?, b"key");
+        assert_eq!(trie.get(&[9])?, vec![9; 40]);
+
+        remove_store(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+use std::error::Error;
+
+use tiny_keccak::Hasher;
+
+// The header takes up the whole first page of a store file, so records start
+// on the next page.
+pub(crate) const HEADER_SIZE: usize = 4096;
+
+const FILE_MAGIC: &[u8; 8] = b"fftrie\x00\x00";
+const FORMAT_VERSION: u32 = 3;
+
+// The superblock is double-buffered: commits alternate between the two slots,
+// so a write torn by a crash can only ever damage the newer one.
+const SLOT_OFFSETS: [usize; 2] = [128, 256];
+const SLOT_SIZE: usize = 64;
+const SLOT_DATA_SIZE: usize = 56;
+
+/// The latest durably committed root, as recorded in the store header.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Superblock {
+    pub seq: u64,
+    pub root: u64,
+    pub root_hash: [u8; 32],
+    // End of the data belonging to this commit. Anything past it is discarded
+    // when the store is reopened.
+    pub end: u64,
+}
+
+impl Superblock {
+    pub fn slot_offset(&self) -> u64 {
+        SLOT_OFFSETS[(self.seq % 2) as usize] as u64
+    }
+
+    pub fn encode(&self) -> [u8; SLOT_SIZE] {
+        let mut out = [0u8; SLOT_SIZE];
+        out[0..8].copy_from_slice(&self.seq.to_be_bytes());
+        out[8..16].copy_from_slice(&self.root.to_be_bytes());
+        out[16..48].copy_from_slice(&self.root_hash);
+        out[48..56].copy_from_slice(&self.end.to_be_bytes());
+        let sum = checksum(&out[..SLOT_DATA_SIZE]);
+        out[56..64].copy_from_slice(&sum);
+        out
+    }
+
+    fn decode(slot: &[u8]) -> Option<Self> {
+        if slot[56..64] != checksum(&slot[..SLOT_DATA_SIZE]) {
+            return None;
+        }
+
+        let mut root_hash = [0u8; 32];
+        root_hash.copy_from_slice(&slot[16..48]);
+        Some(Self {
+            seq: u64::from_be_bytes(slot[0..8].try_into().unwrap()),
+            root: u64::from_be_bytes(slot[8..16].try_into().unwrap()),
+            root_hash,
+            end: u64::from_be_bytes(slot[48..56].try_into().unwrap()),
+        })
+    }
+}
+
+/// The fixed part of the header, which only changes when the whole file is
+/// replaced by compaction.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Header {
+    // Bumped by every compaction so that segment files from before and after
+    // it never share a name.
+    pub generation: u32,
+    pub segment_size: u64,
+    pub superblock: Option<Superblock>,
+}
+
+/// A fresh header with no committed root.
+pub(crate) fn new_header(generation: u32, segment_size: u64) -> Vec<u8> {
+    let mut header = vec![0u8; HEADER_SIZE];
+    header[..8].copy_from_slice(FILE_MAGIC);
+    header[8..12].copy_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header[12..16].copy_from_slice(&generation.to_be_bytes());
+    header[16..24].copy_from_slice(&segment_size.to_be_bytes());
+    header
+}
+
+/// Validates the header at the start of `data` and returns it along with the
+/// newest intact superblock, if any commit has been recorded.
+pub(crate) fn read_header(data: &[u8]) -> Result<Header, Box<dyn Error>> {
+    if data.len() < HEADER_SIZE || &data[..8] != FILE_MAGIC {
+        return Err("not an fftrie store".into());
+    }
+
+    let version = u32::from_be_bytes(data[8..12].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(format!("unsupported fftrie store version {}", version).into());
+    }
+
+    Ok(Header {
+        generation: u32::from_be_bytes(data[12..16].try_into().unwrap()),
+        segment_size: u64::from_be_bytes(data[16..24].try_into().unwrap()),
+        superblock: SLOT_OFFSETS.iter()
+            .filter_map(|offset| Superblock::decode(&data[*offset..*offset + SLOT_SIZE]))
+            .max_by_key(|sb| sb.seq),
+    })
+}
+
+fn checksum(data: &[u8]) -> [u8; 8] {
+    let mut hasher = tiny_keccak::Keccak::v256();
+    hasher.update(data);
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+    hash[..8].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newest_intact_slot_wins() -> Result<(), Box<dyn Error>> {
+        let mut header = new_header(3, 1 << 20);
+        assert_eq!(read_header(&header)?, Header { generation: 3, segment_size: 1 << 20, superblock: None });
+
+        let older = Superblock { seq: 1, root: 4096, root_hash: [1; 32], end: 5000 };
+        let newer = Superblock { seq: 2, root: 5000, root_hash: [2; 32], end: 6000 };
+        for sb in [older, newer] {
+            let offset = sb.slot_offset() as usize;
+            header[offset..offset + SLOT_SIZE].copy_from_slice(&sb.encode());
+        }
+        assert_eq!(read_header(&header)?.superblock, Some(newer));
+
+        // Tearing the newer slot falls back to the older one.
+        header[newer.slot_offset() as usize + 3] ^= 0xff;
+        assert_eq!(read_header(&header)?.superblock, Some(older));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let mut header = new_header(0, 1 << 20);
+        header[8..12].copy_from_slice(&1u32.to_be_bytes());
+        assert!(read_header(&header).is_err());
+        assert!(read_header(b"fftrie").is_err());
+    }
+}
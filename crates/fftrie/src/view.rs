@@ -0,0 +1,222 @@
+use std::error::Error;
+
+use crate::nibbles::NibbleSlice;
+use crate::node::Node;
+
+/// A node read in place from its encoded record, without copying its path,
+/// value or children out.
+#[derive(Clone, Copy)]
+pub struct NodeView<'a> {
+    record: &'a [u8],
+    kind: ViewKind<'a>,
+    hash: &'a [u8],
+}
+
+#[derive(Clone, Copy)]
+enum ViewKind<'a> {
+    // The encoded children, which are variable-length and so are walked on
+    // demand.
+    Branch { children: &'a [u8], value: Option<&'a [u8]> },
+    Leaf { path: NibbleSlice<'a>, value: &'a [u8] },
+    Extension { path: NibbleSlice<'a>, child: &'a [u8] },
+}
+
+/// A child as seen through a `NodeView`.
+#[derive(Clone, Copy)]
+pub enum ChildView<'a> {
+    Empty,
+    Stored(u64),
+    Inline(NodeView<'a>),
+}
+
+impl<'a> NodeView<'a> {
+    /// Views a record in the format written by `Node::to_writer`.
+    pub fn new(record: &'a [u8]) -> Result<Self, Box<dyn Error>> {
+        let mut reader = Reader { data: record, pos: 1 };
+        let kind = match record.first() {
+            Some(0) => {
+                let start = reader.pos;
+                for _ in 0..16 {
+                    reader.skip_child()?;
+                }
+                let children = &record[start..reader.pos];
+                let value = reader.value()?;
+                ViewKind::Branch { children, value: (!value.is_empty()).then_some(value) }
+            }
+            Some(1) => ViewKind::Leaf { path: reader.path()?, value: reader.value()? },
+            Some(2) => {
+                let path = reader.path()?;
+                let start = reader.pos;
+                reader.skip_child()?;
+                ViewKind::Extension { path, child: &record[start..reader.pos] }
+            }
+            _ => return Err("invalid node type".into()),
+        };
+
+        let hash = &record[reader.pos..];
+        if hash.len() > 32 {
+            return Err("node hash too long".into());
+        }
+
+        Ok(Self { record, kind, hash })
+    }
+
+    pub fn is_branch(&self) -> bool {
+        matches!(self.kind, ViewKind::Branch { .. })
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        matches!(self.kind, ViewKind::Leaf { .. })
+    }
+
+    /// The path of a leaf or extension.
+    pub fn path(&self) -> Option<NibbleSlice<'a>> {
+        match self.kind {
+            ViewKind::Leaf { path, .. } | ViewKind::Extension { path, .. } => Some(path),
+            ViewKind::Branch { .. } => None,
+        }
+    }
+
+    /// The value of a leaf, or of a branch that has one.
+    pub fn value(&self) -> Option<&'a [u8]> {
+        match self.kind {
+            ViewKind::Leaf { value, .. } => Some(value),
+            ViewKind::Branch { value, .. } => value,
+            ViewKind::Extension { .. } => None,
+        }
+    }
+
+    /// The child of a branch at `nibble`, or of an extension, whatever the
+    /// nibble.
+    pub fn child(&self, nibble: usize) -> Result<ChildView<'a>, Box<dyn Error>> {
+        match self.kind {
+            ViewKind::Branch { children, .. } => {
+                let mut reader = Reader { data: children, pos: 0 };
+                for _ in 0..nibble {
+                    reader.skip_child()?;
+                }
+                reader.child()
+            }
+            ViewKind::Extension { child, .. } => Reader { data: child, pos: 0 }.child(),
+            ViewKind::Leaf { .. } => Ok(ChildView::Empty),
+        }
+    }
+
+    pub fn hash(&self) -> &'a [u8] {
+        self.hash
+    }
+
+    /// Decodes the viewed record into an owned node.
+    pub fn to_node(self) -> Result<Node, Box<dyn Error>> {
+        Node::from_slice(self.record)
+    }
+}
+
+// Reads the fields of an encoded record, failing on anything truncated.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Everything not read yet.
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos.min(self.data.len())..]
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or("truncated record")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<usize, Box<dyn Error>> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    pub(crate) fn path(&mut self) -> Result<NibbleSlice<'a>, Box<dyn Error>> {
+        let len = self.take(1)?[0] as usize;
+        Ok(NibbleSlice::from_packed(self.take(len.div_ceil(2))?, len))
+    }
+
+    pub(crate) fn value(&mut self) -> Result<&'a [u8], Box<dyn Error>> {
+        let len = self.u16()?;
+        self.take(len)
+    }
+
+    fn child(&mut self) -> Result<ChildView<'a>, Box<dyn Error>> {
+        match self.take(1)?[0] {
+            0 => Ok(ChildView::Empty),
+            1 => Ok(ChildView::Stored(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))),
+            2 => {
+                let len = self.u16()?;
+                Ok(ChildView::Inline(NodeView::new(self.take(len)?)?))
+            }
+            _ => Err("invalid node reference".into()),
+        }
+    }
+
+    fn skip_child(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.take(1)?[0] {
+            0 => {}
+            1 => {
+                self.take(8)?;
+            }
+            2 => {
+                let len = self.u16()?;
+                self.take(len)?;
+            }
+            _ => return Err("invalid node reference".into()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nibbles::Nibbles;
+    use crate::node::{Branch, Leaf, NodeHash, NodeRef};
+
+    use super::*;
+
+    #[test]
+    fn test_view_matches_node() -> Result<(), Box<dyn Error>> {
+        let mut inline = Node::Leaf(Leaf::new(Nibbles::from_bytes(b"a"), b"1".to_vec()));
+        inline.set_hash(NodeHash::new(&[0xc0; 8]));
+
+        let mut branch = Branch::new();
+        branch.children[2] = NodeRef::Inline(Box::new(inline));
+        branch.children[9] = NodeRef::Stored(4242);
+        branch.value = Some(b"branch value".to_vec());
+        let mut branch = Node::Branch(branch);
+        branch.set_hash(NodeHash::from([0xab; 32]));
+
+        let mut record = Vec::new();
+        branch.to_writer(&mut record)?;
+        let view = NodeView::new(&record)?;
+
+        assert!(view.is_branch());
+        assert_eq!(view.value(), Some(&b"branch value"[..]));
+        assert_eq!(view.hash(), [0xab; 32]);
+        assert!(matches!(view.child(0)?, ChildView::Empty));
+        assert!(matches!(view.child(9)?, ChildView::Stored(4242)));
+        match view.child(2)? {
+            ChildView::Inline(leaf) => {
+                assert!(leaf.is_leaf());
+                assert_eq!(leaf.path().unwrap().to_nibbles(), Nibbles::from_bytes(b"a"));
+                assert_eq!(leaf.value(), Some(&b"1"[..]));
+                assert_eq!(leaf.hash(), [0xc0; 8]);
+            }
+            _ => panic!("expected an inline child"),
+        }
+
+        assert!(view.to_node()?.hash() == branch.hash());
+        assert!(NodeView::new(&record[..record.len() - 40]).is_err());
+        Ok(())
+    }
+}
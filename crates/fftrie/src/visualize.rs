@@ -0,0 +1,225 @@
+use std::error::Error;
+use std::fmt::Write;
+
+use serde_json::{json, Map, Value};
+
+use crate::nibbles::Nibbles;
+use crate::node::{Node, NodeRef};
+use crate::store::Store;
+use crate::Trie;
+
+/// Limits on how much of a trie `Trie::to_json` and `Trie::to_dot` show.
+#[derive(Clone, Debug, Default)]
+pub struct DumpOptions {
+    /// How many levels of nodes to show. Children below the last level are
+    /// shown as elided.
+    pub max_depth: Option<usize>,
+    /// Only show the nodes on the way to these nibbles and the subtree below
+    /// them.
+    pub prefix: Vec<u8>,
+}
+
+impl<S: Store + ?Sized> Trie<S> {
+    /// Describes the trie as nested JSON objects, one per node, with its
+    /// type, where it lives, its path, a short hash, its flags and its value.
+    /// Works on uncommitted tries as well, whose new nodes are shown by their
+    /// arena index. An empty trie is `null`.
+    pub fn to_json(&self, options: &DumpOptions) -> Result<Value, Box<dyn Error>> {
+        if self.root.is_empty() {
+            return Ok(Value::Null);
+        }
+        self.describe(&self.root, &mut Nibbles::default(), 0, options)
+    }
+
+    /// Renders the same view as `to_json` as a Graphviz digraph, with the
+    /// nodes still in the arena highlighted.
+    pub fn to_dot(&self, options: &DumpOptions) -> Result<String, Box<dyn Error>> {
+        let mut out = String::from("digraph trie {\n    node [shape=box, fontname=monospace];\n");
+        let json = self.to_json(options)?;
+        if !json.is_null() {
+            dot_node(&json, &mut 0, &mut out);
+        }
+        out.push_str("}\n");
+        Ok(out)
+    }
+
+    fn describe(&self, node_ref: &NodeRef, position: &mut Nibbles, depth: usize, options: &DumpOptions) -> Result<Value, Box<dyn Error>> {
+        let mut object = Map::new();
+        match node_ref {
+            NodeRef::Stored(offset) => object.insert("offset".into(), json!(offset)),
+            NodeRef::Dirty(index) => object.insert("arena".into(), json!(index)),
+            NodeRef::Inline(_) => object.insert("inline".into(), json!(true)),
+            NodeRef::Empty => return Err("node not found".into()),
+        };
+
+        let node = self.get_node(node_ref)?;
+        object.insert("type".into(), json!(node.to_string().to_lowercase()));
+        object.insert("at".into(), json!(nibbles_hex(position)));
+        object.insert("hash".into(), json!(node.hash().map(|hash| hex::encode(&hash[..hash.len().min(4)]))));
+        object.insert("dirty".into(), json!(node.is_dirty()));
+        object.insert("committed".into(), json!(node.is_committed()));
+
+        let elide = options.max_depth.is_some_and(|max| depth + 1 >= max);
+        match &node {
+            Node::Leaf(leaf) => {
+                object.insert("path".into(), json!(nibbles_hex(&leaf.path)));
+                object.insert("value".into(), json!(format!("0x{}", hex::encode(&leaf.value))));
+            }
+            Node::Extension(ext) => {
+                object.insert("path".into(), json!(nibbles_hex(&ext.path)));
+                let len = position.len();
+                position.extend(&ext.path);
+                if matches_prefix(position, &options.prefix) {
+                    let child = if elide { elided(&ext.child) } else { self.describe(&ext.child, position, depth + 1, options)? };
+                    object.insert("child".into(), child);
+                }
+                position.truncate(len);
+            }
+            Node::Branch(branch) => {
+                if let Some(value) = &branch.value {
+                    object.insert("value".into(), json!(format!("0x{}", hex::encode(value))));
+                }
+
+                let mut children = Map::new();
+                for (nibble, child) in branch.children.iter().enumerate().filter(|(_, child)| !child.is_empty()) {
+                    position.push(nibble as u8);
+                    if matches_prefix(position, &options.prefix) {
+                        let child = if elide { elided(child) } else { self.describe(child, position, depth + 1, options)? };
+                        children.insert(format!("{:x}", nibble), child);
+                    }
+                    position.truncate(position.len() - 1);
+                }
+                object.insert("children".into(), Value::Object(children));
+            }
+        }
+
+        Ok(Value::Object(object))
+    }
+}
+
+// Whether a node at `position` is on the way to `prefix` or below it.
+fn matches_prefix(position: &Nibbles, prefix: &[u8]) -> bool {
+    (0..position.len().min(prefix.len())).all(|i| position.at(i) == prefix[i] as usize)
+}
+
+fn elided(node_ref: &NodeRef) -> Value {
+    match node_ref.offset() {
+        Some(offset) => json!({ "elided": true, "offset": offset }),
+        None => json!({ "elided": true }),
+    }
+}
+
+fn nibbles_hex(nibbles: &Nibbles) -> String {
+    (0..nibbles.len()).map(|i| format!("{:x}", nibbles.at(i))).collect()
+}
+
+// Writes the node described by `json` and everything below it, returning its
+// id in the graph.
+fn dot_node(json: &Value, next_id: &mut usize, out: &mut String) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+
+    if json["elided"] == true {
+        let _ = writeln!(out, "    n{} [label=\"...\", style=dashed];", id);
+        return id;
+    }
+
+    let mut label = json["type"].as_str().unwrap_or_default().to_string();
+    if let Some(offset) = json["offset"].as_u64() {
+        let _ = write!(label, " @{}", offset);
+    } else if let Some(index) = json["arena"].as_u64() {
+        let _ = write!(label, " #{}", index);
+    } else if json["inline"] == true {
+        label.push_str(" inline");
+    }
+    if let Some(hash) = json["hash"].as_str() {
+        let _ = write!(label, "\\nhash {}", hash);
+    }
+    if let Some(path) = json["path"].as_str() {
+        let _ = write!(label, "\\npath {}", path);
+    }
+    if let Some(value) = json["value"].as_str() {
+        let shown = if value.len() > 34 { format!("{}...", &value[..34]) } else { value.to_string() };
+        let _ = write!(label, "\\nvalue {}", shown);
+    }
+
+    let style = if json["arena"].is_u64() { ", style=filled, fillcolor=lightpink" } else { "" };
+    let _ = writeln!(out, "    n{} [label=\"{}\"{}];", id, label, style);
+
+    if let Some(child) = json.get("child") {
+        let child_id = dot_node(child, next_id, out);
+        let _ = writeln!(out, "    n{} -> n{};", id, child_id);
+    }
+    if let Some(children) = json["children"].as_object() {
+        for (nibble, child) in children {
+            let child_id = dot_node(child, next_id, out);
+            let _ = writeln!(out, "    n{} -> n{} [label=\"{}\"];", id, child_id, nibble);
+        }
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::store::MemoryStore;
+
+    use super::*;
+
+    fn fixture() -> Result<Trie<MemoryStore>, Box<dyn Error>> {
+        let store = Rc::new(RefCell::new(MemoryStore::new()));
+        let mut trie = Trie::new_empty(store);
+        trie.insert(b"do", b"verb")?;
+        trie.insert(b"horse", b"stallion")?;
+        trie.insert(b"doge", b"coin")?;
+        trie.insert(b"dog", b"puppy")?;
+        Ok(trie)
+    }
+
+    #[test]
+    fn test_to_json() -> Result<(), Box<dyn Error>> {
+        let mut trie = fixture()?;
+        let json = trie.to_json(&DumpOptions::default())?;
+        assert_eq!(json["type"], "extension");
+        assert_eq!(json["arena"], 0);
+        assert_eq!(json["path"], "6");
+        assert_eq!(json["child"]["type"], "branch");
+        assert_eq!(json["child"]["at"], "6");
+        let horse = &json["child"]["children"]["8"];
+        assert_eq!(horse["type"], "leaf");
+        assert_eq!(horse["value"], "0x7374616c6c696f6e");
+
+        // Depth and prefix limits.
+        let shallow = trie.to_json(&DumpOptions { max_depth: Some(2), ..Default::default() })?;
+        assert_eq!(shallow["child"]["children"]["8"]["elided"], true);
+        let dog = trie.to_json(&DumpOptions { prefix: vec![6, 4], ..Default::default() })?;
+        let children = dog["child"]["children"].as_object().unwrap();
+        assert_eq!(children.keys().collect::<Vec<_>>(), ["4"]);
+
+        // Once committed, nodes are shown where they are stored.
+        let result = trie.commit()?;
+        let json = trie.to_json(&DumpOptions::default())?;
+        assert_eq!(json["offset"], result.root_offset);
+        assert_eq!(json["hash"], hex::encode(&result.root_hash[..4]));
+        assert_eq!(json["dirty"], false);
+        assert_eq!(json["committed"], true);
+        assert!(!trie.to_dot(&DumpOptions::default())?.contains("lightpink"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_dot() -> Result<(), Box<dyn Error>> {
+        let trie = fixture()?;
+        let dot = trie.to_dot(&DumpOptions::default())?;
+        assert!(dot.starts_with("digraph trie {"));
+        assert!(dot.contains("n0 [label=\"extension #0\\npath 6\", style=filled, fillcolor=lightpink];"));
+        assert!(dot.contains("n0 -> n1;"));
+        assert!(dot.contains("[label=\"8\"];"));
+
+        let empty = Trie::new_empty(Rc::new(RefCell::new(MemoryStore::new())));
+        assert_eq!(empty.to_dot(&DumpOptions::default())?, "digraph trie {\n    node [shape=box, fontname=monospace];\n}\n");
+        Ok(())
+    }
+}
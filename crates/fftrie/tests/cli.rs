@@ -0,0 +1,78 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::process::{Command, Output};
+use std::rc::Rc;
+
+use fftrie::store::{FileStore, SyncMode};
+use fftrie::{CommitResult, Trie};
+
+fn fftrie(args: &[&str]) -> Result<Output, Box<dyn Error>> {
+    Ok(Command::new(env!("CARGO_BIN_EXE_fftrie")).args(args).output()?)
+}
+
+fn create_store(name: &str) -> Result<(String, CommitResult), Box<dyn Error>> {
+    let path = std::env::temp_dir().join(format!("fftrie-cli-{}-{}.db", name, std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let mut trie = Trie::new_empty(Rc::new(RefCell::new(FileStore::new(&path)?)));
+    trie.insert(b"do", b"verb")?;
+    trie.insert(b"horse", b"stallion")?;
+    trie.insert(b"doge", b"coin")?;
+    trie.insert(b"dog", b"puppy")?;
+    let result = trie.commit()?;
+    Ok((path, result))
+}
+
+#[test]
+fn test_bad_offsets_are_errors() -> Result<(), Box<dyn Error>> {
+    let (path, result) = create_store("bad-offset")?;
+
+    let output = fftrie(&["root", &path])?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?.trim(), format!("0x{}", hex::encode(result.root_hash)));
+
+    // An offset in the middle of a record is reported, not decoded.
+    let bad = (result.root_offset + 2).to_string();
+    for args in [vec!["root", &path, &bad], vec!["get", &path, "646f", "--root", &bad]] {
+        let output = fftrie(&args)?;
+        let stderr = String::from_utf8(output.stderr)?;
+        assert_eq!(output.status.code(), Some(1), "{:?}", args);
+        assert!(stderr.starts_with("fftrie: ") && !stderr.contains("panicked"), "{:?}: {}", args, stderr);
+    }
+
+    let output = fftrie(&["check", &path, "--root", &bad])?;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stdout)?.contains("unreadable node"));
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn test_inspecting_leaves_the_store_untouched() -> Result<(), Box<dyn Error>> {
+    let (path, result) = create_store("untouched")?;
+
+    // A commit past the durable root, which opening the store for writing
+    // would roll back.
+    let store = Rc::new(RefCell::new(FileStore::open(&path)?));
+    store.borrow_mut().set_sync_mode(SyncMode::None);
+    let mut trie = Trie::new(Rc::clone(&store), Some(result.root_offset));
+    trie.insert(b"doe", b"reindeer")?;
+    trie.commit()?;
+    drop(trie);
+    drop(store);
+    let before = std::fs::read(&path)?;
+
+    for command in ["root", "dump", "stats", "check"] {
+        let output = fftrie(&[command, &path])?;
+        assert!(output.status.success(), "{}: {}", command, String::from_utf8_lossy(&output.stderr));
+    }
+    assert!(std::fs::read(&path)? == before);
+
+    // Keeping no roots would empty the store.
+    let output = fftrie(&["compact", &path, "--keep", "0"])?;
+    assert_eq!(output.status.code(), Some(1));
+    assert!(std::fs::read(&path)? == before);
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,161 @@
+// Runs the TrieTests fixtures from ethereum/tests, vendored under
+// testdata/TrieTests, against `Trie`.
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+
+use fftrie::store::MemoryStore;
+use fftrie::Trie;
+use serde_json::Value;
+use tiny_keccak::Hasher;
+
+const TRIE_TEST: &str = include_str!("../testdata/TrieTests/trietest.json");
+const TRIE_ANY_ORDER: &str = include_str!("../testdata/TrieTests/trieanyorder.json");
+const SECURE_TRIE_TEST: &str = "testdata/TrieTests/hex_encoded_securetrie_test.json";
+
+// Every order of the entries is tried for cases up to this size, and this
+// many seeded shuffles of them above it.
+const MAX_PERMUTED: usize = 6;
+const SHUFFLES: usize = 50;
+
+// Fixture strings are hex when they start with 0x, and raw bytes otherwise.
+fn bytes(value: &Value) -> Result<Vec<u8>, Box<dyn Error>> {
+    let value = value.as_str().ok_or("expected a string")?;
+    match value.strip_prefix("0x") {
+        Some(hex) => Ok(hex::decode(hex)?),
+        None => Ok(value.as_bytes().to_vec()),
+    }
+}
+
+fn cases(fixture: &str) -> Result<Vec<(String, Value)>, Box<dyn Error>> {
+    let Value::Object(cases) = serde_json::from_str(fixture)? else {
+        return Err("expected an object of cases".into());
+    };
+    Ok(cases.into_iter().collect())
+}
+
+fn keccak(data: &[u8]) -> Vec<u8> {
+    let mut hash = [0u8; 32];
+    let mut hasher = tiny_keccak::Keccak::v256();
+    hasher.update(data);
+    hasher.finalize(&mut hash);
+    hash.to_vec()
+}
+
+// Applies `ops` in order, where a `None` value removes the key, and checks the
+// root both before and after committing. Empty tries can't be committed.
+fn check_root(name: &str, ops: &[(Vec<u8>, Option<Vec<u8>>)], expected: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut trie = Trie::new_empty(Rc::new(RefCell::new(MemoryStore::new())));
+    for (key, value) in ops {
+        match value {
+            Some(value) => trie.insert(key, value)?,
+            None => _ = trie.remove(key)?,
+        }
+    }
+    assert_eq!(hex::encode(trie.calculate_root()?), hex::encode(expected), "{}", name);
+    if trie.iter().next().is_some() {
+        assert_eq!(hex::encode(trie.commit()?.root_hash), hex::encode(expected), "{}: committed", name);
+    }
+    Ok(())
+}
+
+// The orders `check_root` tries the entries of an any-order case in.
+fn orders(len: usize, seed: &[u8]) -> Vec<Vec<usize>> {
+    if len <= MAX_PERMUTED {
+        return permutations(&(0..len).collect::<Vec<_>>());
+    }
+
+    let mut state = hmac_sha256::Hash::hash(seed);
+    (0..SHUFFLES)
+        .map(|_| {
+            let mut order: Vec<usize> = (0..len).collect();
+            for i in (1..len).rev() {
+                state = hmac_sha256::Hash::hash(&state);
+                let j = u64::from_be_bytes(state[..8].try_into().unwrap()) as usize % (i + 1);
+                order.swap(i, j);
+            }
+            order
+        })
+        .collect()
+}
+
+fn permutations(items: &[usize]) -> Vec<Vec<usize>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+    let mut out = Vec::new();
+    for i in 0..items.len() {
+        let mut rest = items.to_vec();
+        let first = rest.remove(i);
+        for mut permutation in permutations(&rest) {
+            permutation.insert(0, first);
+            out.push(permutation);
+        }
+    }
+    out
+}
+
+// Runs cases whose input is an object of entries, inserted in every order
+// `orders` gives, with keys passed through `key`.
+fn run_any_order(fixture: &str, key: impl Fn(Vec<u8>) -> Vec<u8>) -> Result<(), Box<dyn Error>> {
+    for (name, case) in cases(fixture)? {
+        let expected = bytes(&case["root"])?;
+        let entries: Vec<(Vec<u8>, Option<Vec<u8>>)> = case["in"].as_object().ok_or("expected an object of entries")?
+            .iter()
+            .map(|(k, value)| Ok((key(bytes(&Value::from(k.as_str()))?), Some(bytes(value)?))))
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        for order in orders(entries.len(), name.as_bytes()) {
+            let ordered: Vec<_> = order.iter().map(|&i| entries[i].clone()).collect();
+            check_root(&format!("{} in order {:?}", name, order), &ordered, &expected)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_shuffles_are_seeded_permutations() {
+    let shuffles = orders(10, b"case");
+    assert_eq!(shuffles.len(), SHUFFLES);
+    assert_eq!(shuffles, orders(10, b"case"));
+    assert_ne!(shuffles, orders(10, b"other case"));
+    for order in &shuffles {
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+    }
+    assert_eq!(orders(3, b"case").len(), 6);
+}
+
+#[test]
+fn test_trietest() -> Result<(), Box<dyn Error>> {
+    for (name, case) in cases(TRIE_TEST)? {
+        let expected = bytes(&case["root"])?;
+        let ops: Vec<(Vec<u8>, Option<Vec<u8>>)> = case["in"].as_array().ok_or("expected a list of operations")?
+            .iter()
+            .map(|op| {
+                // A null or empty value removes the key.
+                let value = match &op[1] {
+                    Value::Null => None,
+                    value => Some(bytes(value)?).filter(|value| !value.is_empty()),
+                };
+                Ok((bytes(&op[0])?, value))
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        check_root(&name, &ops, &expected)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_trieanyorder() -> Result<(), Box<dyn Error>> {
+    run_any_order(TRIE_ANY_ORDER, |key| key)
+}
+
+#[test]
+#[ignore = "hex_encoded_securetrie_test.json is not vendored yet"]
+fn test_hex_encoded_securetrie_test() -> Result<(), Box<dyn Error>> {
+    let fixture = std::fs::read_to_string(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(SECURE_TRIE_TEST))?;
+    run_any_order(&fixture, |key| keccak(&key))
+}